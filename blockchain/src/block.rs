@@ -1,4 +1,5 @@
 use bincode::{deserialize, serialize};
+use std::collections::{HashMap, HashSet};
 use model::*;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
@@ -7,6 +8,9 @@ use utils::Error;
 use utils::hash;
 use utils::Size;
 
+pub const MAX_BLOCK_SIZE: u64 = 1_000_000;
+pub const BLOCK_REWARD: u64 = 42_000_000;
+
 #[derive(Debug, Clone)]
 pub struct Block {
     pub version: u32,
@@ -72,26 +76,11 @@ impl Block {
     }
 
     /**
-     *  transforme les hash du tableau de transactions en chaîne de caractères
-     **/
-    fn hash_transactions(&self) -> Result<Vec<u8>, Error> {
-        let mut buffer = Vec::new();
-
-        for tx in &self.transactions {
-            buffer.append(&mut tx.hash()?);
-        }
-
-        Ok(buffer)
-    }
-
-    /**
-     *  calcule le hash d'un bloc
+     *  calcule le hash d'un bloc, les transactions sont engagées par la racine de merkle
+     *  donc le hash de l'en-tête suffit à identifier le bloc
      **/
     pub fn hash(&self) -> Result<Vec<u8>, Error> {
-        let mut block = self.send_header()?;
-        block.append(&mut self.hash_transactions()?);
-        let result = hash::hash(block);
-        Ok(hash::hash(result))
+        self.hash_header()
     }
 
     pub fn hash_header(&self) -> Result<Vec<u8>, Error> {
@@ -100,6 +89,29 @@ impl Block {
         Ok(hash::hash(result))
     }
 
+    pub fn check_pow(&self) -> Result<bool, Error> {
+        Ok(hash::hash_meets_target(&self.hash_header()?, &self.difficulty))
+    }
+
+    /**
+     *  vérifie qu'un en-tête suit bien le bloc précédent : chaînage, hauteur, difficulté,
+     *  horodatage et preuve de travail
+     **/
+    pub fn check_header(&self, previous: &Block) -> Result<bool, Error> {
+        if self.previous_hash != previous.hash()? || self.height != previous.height + 1 {
+            return Ok(false)
+        }
+        // la difficulté n'est pas encore réajustée, elle reste celle du bloc précédent
+        if self.difficulty != previous.difficulty {
+            return Ok(false)
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if self.timestamp >= now + 7200 {
+            return Ok(false)
+        }
+        self.check_pow()
+    }
+
     pub fn is_sane(&self) -> bool {
         if self.transactions.is_empty() {
             return false;
@@ -126,24 +138,69 @@ impl Block {
         true
     }
 
-    pub fn is_valid(&self) -> bool {
-        if !self.is_sane() {
-            return false;
+    /**
+     *  vérifie les transactions d'un bloc qui prolonge le sommet de la chaîne principale :
+     *  une seule coinbase en tête, qui ne crée pas plus que la récompense et les frais,
+     *  des entrées non dépensées (dans les utxos ou plus tôt dans le bloc) aux scripts
+     *  valides, et des sorties qui ne valent pas plus que les entrées
+     **/
+    pub fn is_valid(&self) -> Result<bool, Error> {
+        if !self.is_sane() || !self.transactions[0].is_coinbase() {
+            return Ok(false)
         }
 
-        // valid coinbase
-
-        // valid txs
-        let mut utxos : Vec<TxOut>;
-        let mut tx_txo : super::transaction::TxTxo;
+        // outputs created earlier in the block, later transactions may spend them
+        let mut created : HashMap<Outpoint, TxOut> = HashMap::new();
+        let mut spent = HashSet::new();
+        let mut fees : u64 = 0;
         for tx in &self.transactions[1..] {
-            utxos = super::Utxos::get_utxos(tx.hash().unwrap()).unwrap();
-            tx_txo = TxTxo::new(tx, utxos);
-            if !tx_txo.is_valid() {
-                return false
+            if tx.is_coinbase() || !tx.is_sane() {
+                return Ok(false)
+            }
+            let hash = tx.hash()?;
+            if super::Utxos::tx_exist(hash.clone())? {
+                return Ok(false)
+            }
+
+            let mut input_sum : u64 = 0;
+            for input in &tx.inputs {
+                let outpoint = &input.previous_output;
+                if !spent.insert(outpoint.clone()) {
+                    return Ok(false)
+                }
+                let txo = match created.remove(outpoint) {
+                    Some(txo) => txo,
+                    None => match super::Utxos::get_utxo(outpoint)? {
+                        Some(txo) => txo,
+                        None => return Ok(false),
+                    },
+                };
+                if !super::scripts::verify_script(input.script.value.as_bytes()) {
+                    return Ok(false)
+                }
+                input_sum = match input_sum.checked_add(txo.value) {
+                    Some(sum) => sum,
+                    None => return Ok(false),
+                };
+            }
+            let output_sum = match sum_values(&tx.outputs) {
+                Some(sum) if sum <= input_sum => sum,
+                _ => return Ok(false),
+            };
+            fees = match fees.checked_add(input_sum - output_sum) {
+                Some(fees) => fees,
+                None => return Ok(false),
+            };
+
+            for (index, output) in tx.outputs.iter().enumerate() {
+                created.insert(Outpoint { hash: hash.clone(), index: index as u32 }, output.clone());
             }
         }
-        true
+
+        match sum_values(&self.transactions[0].outputs) {
+            Some(value) => Ok(value <= BLOCK_REWARD.saturating_add(fees)),
+            None => Ok(false),
+        }
     }
 
     pub fn send_header(&self) -> Result<Vec<u8>, Error> {
@@ -181,9 +238,8 @@ impl Block {
     pub fn send_tx(&self) -> Result<Vec<u8>, Error> {
         let mut buffer = Vec::new();
 
-        let mut tx_count = serialize(&self.transactions.len())?;
-        tx_count.reverse();
-        buffer.append(&mut tx_count);
+        let tx_count = VarUint::from_u64(self.transactions.len() as u64);
+        buffer.append(&mut tx_count.send());
 
         for tx in &self.transactions {
             buffer.append(&mut tx.send()?);
@@ -199,48 +255,48 @@ impl Block {
     }
 
     pub fn read(buffer: &[u8]) -> Result<Block, Error> {
-        let mut version = buffer[0..4].to_vec();
+        let mut version = take(buffer, 0, 4)?.to_vec();
         version.reverse();
         let version: u32 = deserialize(&version)?;
 
-        let flags_count = VarUint::new(&buffer[4..].to_vec());
+        let flags_count = VarUint::read(rest(buffer, 4))?;
         let mut flags = Vec::new();
         let mut offset: usize = 4 + flags_count.size() as usize;
         for _ in 0..flags_count.value {
-            let s = VarStr::new(&buffer[offset..].to_vec());
+            let s = VarStr::read(rest(buffer, offset))?;
             offset += s.size() as usize;
             flags.push(s);
         }
 
-        let prev_block = buffer[offset..offset + 32].to_vec();
+        let prev_block = take(buffer, offset, 32)?.to_vec();
         offset += 32;
-        let merkle_root = buffer[offset..offset + 32].to_vec();
+        let merkle_root = take(buffer, offset, 32)?.to_vec();
         offset += 32;
 
-        let mut timestamp = buffer[offset..offset + 8].to_vec();
+        let mut timestamp = take(buffer, offset, 8)?.to_vec();
         timestamp.reverse();
         let timestamp = deserialize(&timestamp)?;
         offset += 8;
 
-        let mut height = buffer[offset..offset + 4].to_vec();
+        let mut height = take(buffer, offset, 4)?.to_vec();
         height.reverse();
         let height = deserialize(&height)?;
         offset += 4;
 
-        let target = buffer[offset..offset + 32].to_vec();
+        let target = take(buffer, offset, 32)?.to_vec();
         offset += 32;
 
-        let mut nonce = buffer[offset..offset + 8].to_vec();
+        let mut nonce = take(buffer, offset, 8)?.to_vec();
         nonce.reverse();
         let nonce = deserialize(&nonce)?;
         offset += 8;
 
-        let tx_count = VarUint::new(&buffer[offset..].to_vec());
+        let tx_count = VarUint::read(rest(buffer, offset))?;
         offset += tx_count.size() as usize;
 
         let mut txs = Vec::new();
         for _ in 0..tx_count.value {
-            let tx = Transaction::read(&buffer[offset..].to_vec());
+            let tx = Transaction::read(rest(buffer, offset))?;
             offset += tx.size() as usize;
             txs.push(tx);
         }
@@ -264,6 +320,84 @@ impl Block {
 
 impl Size for Block {
     fn size(&self) -> u64 {
-        1
+        let mut s = 4 + VarUint::from_u64(self.flags.len() as u64).size();
+        for f in &self.flags {
+            s += f.size();
+        }
+        s += 32 + 32 + 8 + 4 + 32 + 8;
+        s += VarUint::from_u64(self.transactions.len() as u64).size();
+        for tx in &self.transactions {
+            s += tx.size();
+        }
+        s
+    }
+}
+
+fn sum_values(outputs: &[TxOut]) -> Option<u64> {
+    outputs.iter().try_fold(0u64, |sum, output| sum.checked_add(output.value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::datadir::TempDataDir;
+    use utils::merkle_tree::compute_merkle_root;
+
+    fn tx(inputs: Vec<Outpoint>, values: &[u64]) -> Transaction {
+        Transaction {
+            version: 0,
+            flags_count: VarUint::from_u64(0),
+            flags: Vec::new(),
+            inputs_count: VarUint::from_u64(inputs.len() as u64),
+            inputs: inputs.into_iter().map(|previous_output| TxIn {
+                previous_output,
+                script: VarStr::from_string(String::new()),
+                shash: Vec::new(),
+            }).collect(),
+            outputs_count: VarUint::from_u64(values.len() as u64),
+            outputs: values.iter().map(|value| TxOut {
+                value: *value,
+                script: VarStr::from_string(String::new()),
+            }).collect(),
+        }
+    }
+
+    fn coinbase(value: u64) -> Transaction {
+        Transaction::coinbase(1, value, VarStr::from_string(String::new()))
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        let merkle_root = compute_merkle_root(transactions.iter().map(|tx| tx.hash().unwrap()).collect());
+        Block {
+            version: 0,
+            flags: Vec::new(),
+            previous_hash: vec![0; 32],
+            merkle_root,
+            timestamp: 0,
+            height: 1,
+            difficulty: vec![0; 32],
+            nonce: 0,
+            transactions,
+            hash: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn transactions_checked_against_utxos() {
+        let _data_dir = TempDataDir::new("block-valid");
+        let funding = Transaction::coinbase(0, 100, VarStr::from_string(String::new()));
+        super::super::Utxos::connect_block(&block(vec![funding.clone()])).unwrap();
+        let funded = Outpoint { hash: funding.hash().unwrap(), index: 0 };
+
+        let spend = tx(vec![funded.clone()], &[60]);
+        let child = tx(vec![Outpoint { hash: spend.hash().unwrap(), index: 0 }], &[50]);
+        assert!(block(vec![coinbase(BLOCK_REWARD + 50), spend.clone(), child.clone()]).is_valid().unwrap());
+        assert!(!block(vec![coinbase(BLOCK_REWARD + 51), spend.clone(), child.clone()]).is_valid().unwrap());
+        assert!(!block(vec![spend.clone(), coinbase(BLOCK_REWARD)]).is_valid().unwrap());
+        // outputs can only be spent after the transaction creating them
+        assert!(!block(vec![coinbase(BLOCK_REWARD), child, spend.clone()]).is_valid().unwrap());
+        assert!(!block(vec![coinbase(BLOCK_REWARD), spend, tx(vec![funded.clone()], &[10])]).is_valid().unwrap());
+        assert!(!block(vec![coinbase(BLOCK_REWARD), tx(vec![funded], &[101])]).is_valid().unwrap());
+        assert!(!block(vec![coinbase(BLOCK_REWARD), tx(vec![Outpoint { hash: vec![7; 32], index: 0 }], &[1])]).is_valid().unwrap());
     }
 }
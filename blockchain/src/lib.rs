@@ -3,10 +3,12 @@ pub use block::Block;
 pub mod scripts;
 pub mod transaction;
 
-use dirs::data_dir;
-use transaction::TxOut;
+use std::collections::HashMap;
+use utils::open_db;
+use transaction::{Outpoint, TxOut};
 use sled::Db;
 use utils::error::Error;
+use utils::Size;
 
 
 //////////////////////////////////////////////////////////////
@@ -19,15 +21,13 @@ pub struct Blockchain;
 
 impl Blockchain {
     fn open() -> Result<Db, Error> {
-        let mut path = data_dir().unwrap();
-        path.push("ensicoin-rust/");
-        path.push("blockchain");
-        Ok(sled::Db::open(path)?)
+        open_db("blockchain")
     }
 
     pub fn add_genesis_block() -> Result<(), Error> {
         let gen = Block::genesis_block()?;
         Blockchain::insert_block(gen.hash_header()?, &gen)?;
+        Utxos::connect_block(&gen)?;
         Ok(())
     }
 
@@ -54,24 +54,120 @@ impl Blockchain {
         }
     }
 
+    pub fn get_tip() -> Result<(Vec<u8>, Block), Error> {
+        let (_, hash) = ChainIndex::tip()?;
+        let block = Blockchain::get_block(&hash)?;
+        Ok((hash, block))
+    }
+
+    // blocks used to be stored under a hash of their header and transaction hashes,
+    // while previous_hash always held the header hash of the parent; stores them
+    // under their header hash, then rebuilds the main chain index, the links along
+    // it and the utxo set from the highest block reaching the genesis
+    pub fn migrate_header_hashes() -> Result<(), Error> {
+        let db = Blockchain::open()?;
+        let mut stored = Vec::new();
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            stored.push((key.to_vec(), Block::read(&value)?));
+        }
+        let mut blocks = HashMap::new();
+        for (key, block) in stored {
+            let hash = block.hash_header()?;
+            if key != hash {
+                db.remove(key)?;
+                db.insert(hash.clone(), block.send()?)?;
+            }
+            blocks.insert(hash, block);
+        }
+        db.flush()?;
+
+        // difficulty is constant, the heaviest chain is the longest one
+        let genesis = Block::genesis_block()?.hash()?;
+        let mut tips: Vec<&Vec<u8>> = blocks.keys().collect();
+        tips.sort_by_key(|h| std::cmp::Reverse(blocks[*h].height));
+        let mut chain = Vec::new();
+        for tip in tips {
+            chain.clear();
+            let mut hash = tip.clone();
+            while let Some(block) = blocks.get(&hash) {
+                chain.push(hash.clone());
+                if block.height == 0 {
+                    break;
+                }
+                hash = block.previous_hash.clone();
+            }
+            if hash == genesis && chain.len() as u32 == blocks[tip].height + 1 {
+                break;
+            }
+            chain.clear();
+        }
+        chain.reverse();
+
+        let index = ChainIndex::open()?;
+        index.clear()?;
+        for (height, hash) in chain.iter().enumerate() {
+            index.insert((height as u32).to_be_bytes(), hash.clone())?;
+        }
+        index.flush()?;
+
+        let next = NextHash::open()?;
+        next.clear()?;
+        Utxos::open()?.clear()?;
+        for (height, hash) in index.iter().values().enumerate() {
+            let hash = hash?.to_vec();
+            if let Some(next_hash) = index.get((height as u32 + 1).to_be_bytes())? {
+                next.insert(hash.clone(), next_hash)?;
+            }
+            Utxos::connect_block(&blocks[&hash])?;
+        }
+        next.flush()?;
+        Ok(())
+    }
+
+    // stores a block, only the genesis is put on the main chain here
     pub fn insert_block(hash: Vec<u8>, block: &Block) -> Result<(), Error> {
         let db = Blockchain::open()?;
-        db.insert(hash, block.send()?)?;
+        db.insert(hash.clone(), block.send()?)?;
         db.flush()?;
-        NextHash::insert_next_hash(block.previous_hash.clone(), block.hash()?)?;
+        if block.height == 0 {
+            ChainIndex::insert(0, hash)?;
+        }
         Ok(())
     }
 
+    pub fn remove_block(hash: &[u8]) -> Result<(), Error> {
+        let db = Blockchain::open()?;
+        db.remove(hash)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    // the stored block extending the tip becomes the new tip
+    pub fn connect_tip(hash: Vec<u8>, block: &Block) -> Result<(), Error> {
+        NextHash::insert_next_hash(block.previous_hash.clone(), hash.clone())?;
+        ChainIndex::insert(block.height, hash)
+    }
+
+    // the tip leaves the main chain, it stays stored; returns it
+    pub fn disconnect_tip() -> Result<Block, Error> {
+        let (height, hash) = ChainIndex::tip()?;
+        if height == 0 {
+            return Err(Error::DBError)
+        }
+        let block = Blockchain::get_block(&hash)?;
+        ChainIndex::remove(height)?;
+        NextHash::remove(&block.previous_hash)?;
+        Ok(block)
+    }
+
 }
 
 // key is a block hash, value is next block's hash
 pub struct NextHash;
 impl NextHash {
     fn open() -> Result<Db, Error> {
-        let mut path = data_dir().unwrap();
-        path.push("ensicoin-rust/");
-        path.push("next_block");
-        Ok(sled::Db::open(path)?)
+        open_db("next_block")
     }
 
     pub fn get_next_hash(hash: &[u8]) -> Result<Vec<u8>, Error> {
@@ -90,16 +186,89 @@ impl NextHash {
 
         Ok(())
     }
+
+    fn remove(hash: &[u8]) -> Result<(), Error> {
+        let db = NextHash::open()?;
+        db.remove(hash)?;
+        db.flush()?;
+        Ok(())
+    }
+}
+
+// key is a block height, value is the hash of the main chain block at this height
+pub struct ChainIndex;
+impl ChainIndex {
+    fn open() -> Result<Db, Error> {
+        open_db("chain_index")
+    }
+
+    pub fn get_hash(height: u32) -> Result<Vec<u8>, Error> {
+        let db = ChainIndex::open()?;
+        match db.get(height.to_be_bytes())? {
+            Some(hash) => Ok(hash.to_vec()),
+            None => Err(Error::DBError),
+        }
+    }
+
+    pub fn insert(height: u32, hash: Vec<u8>) -> Result<(), Error> {
+        let db = ChainIndex::open()?;
+        db.insert(height.to_be_bytes(), hash)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    fn remove(height: u32) -> Result<(), Error> {
+        let db = ChainIndex::open()?;
+        db.remove(height.to_be_bytes())?;
+        db.flush()?;
+        Ok(())
+    }
+
+    // height and hash of the last main chain block
+    pub fn tip() -> Result<(u32, Vec<u8>), Error> {
+        let db = ChainIndex::open()?;
+        if db.is_empty() {
+            ChainIndex::rebuild(&db)?;
+        }
+        match db.last()? {
+            Some((height, hash)) => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&height);
+                Ok((u32::from_be_bytes(bytes), hash.to_vec()))
+            },
+            None => Err(Error::DBError),
+        }
+    }
+
+    pub fn is_main_chain(hash: &[u8], height: u32) -> Result<bool, Error> {
+        match ChainIndex::get_hash(height) {
+            Ok(h) => Ok(h == hash),
+            Err(Error::DBError) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // databases written before the index existed only have NextHash
+    fn rebuild(db: &Db) -> Result<(), Error> {
+        let mut hash = Block::genesis_block()?.hash()?;
+        let mut height : u32 = 0;
+        db.insert(height.to_be_bytes(), hash.clone())?;
+        while let Ok(next) = NextHash::get_next_hash(&hash) {
+            height += 1;
+            db.insert(height.to_be_bytes(), next.clone())?;
+            hash = next;
+        }
+        db.flush()?;
+        Ok(())
+    }
 }
 
 //key is a tx hash, value is a vec of all outputs used as entry for this tx
+//spent outputs are marked with their outpoint as key, the outputs of a tx keep their index
 pub struct Utxos;
 impl Utxos {
     fn open() -> Result<Db, Error> {
-        let mut path = data_dir().unwrap();
-        path.push("ensicoin-rust/");
-        path.push("utxos");
-        Ok(sled::Db::open(path)?)
+        open_db("utxos")
     }
 
     pub fn get_utxos(tx_hash : Vec<u8>) -> Result<Vec<TxOut>, Error> {
@@ -112,15 +281,35 @@ impl Utxos {
         if utxos.is_empty() {
             return Err(Error::DBError)
         }
-        let offset = 0;
+        let mut offset = 0;
         let mut result = Vec::new();
         while offset < utxos.len() {
-            result.push(TxOut::read(&utxos[offset..].to_vec()));
+            let txo = TxOut::read(&utxos[offset..])?;
+            offset += txo.size() as usize;
+            result.push(txo);
         }
 
         Ok(result)
     }
 
+    pub fn get_utxo(outpoint: &Outpoint) -> Result<Option<TxOut>, Error> {
+        if Utxos::open()?.get(outpoint.send()?)?.is_some() {
+            return Ok(None)
+        }
+        match Utxos::get_utxos(outpoint.hash.clone()) {
+            Ok(mut utxos) => {
+                let index = outpoint.index as usize;
+                if index < utxos.len() {
+                    Ok(Some(utxos.swap_remove(index)))
+                } else {
+                    Ok(None)
+                }
+            },
+            Err(Error::NoTxInUtxos) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn tx_exist(tx_hash : Vec<u8>) -> Result<bool, Error> {
         let db = Utxos::open()?;
         let utxo = db.get(tx_hash)?;
@@ -138,4 +327,109 @@ impl Utxos {
 
         Ok(())
     }
+
+    // records the outputs of a main chain block and spends its inputs
+    pub fn connect_block(block: &Block) -> Result<(), Error> {
+        let db = Utxos::open()?;
+        for tx in &block.transactions {
+            let outputs: Vec<u8> = tx.outputs.iter().flat_map(|txo| txo.send()).collect();
+            db.insert(tx.hash()?, outputs)?;
+            if tx.is_coinbase() {
+                continue;
+            }
+            for input in &tx.inputs {
+                db.insert(input.previous_output.send()?, vec![])?;
+            }
+        }
+        db.flush()?;
+        Ok(())
+    }
+
+    // undoes connect_block for a block leaving the main chain
+    pub fn disconnect_block(block: &Block) -> Result<(), Error> {
+        let db = Utxos::open()?;
+        for tx in &block.transactions {
+            db.remove(tx.hash()?)?;
+            if tx.is_coinbase() {
+                continue;
+            }
+            for input in &tx.inputs {
+                db.remove(input.previous_output.send()?)?;
+            }
+        }
+        db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::VarStr;
+    use transaction::Transaction;
+    use utils::datadir::TempDataDir;
+    use utils::merkle_tree::compute_merkle_root;
+
+    fn child(parent: &Block, value: u64) -> Block {
+        let mut block = parent.clone();
+        block.previous_hash = parent.hash().unwrap();
+        block.height = parent.height + 1;
+        block.transactions = vec![Transaction::coinbase(block.height, value, VarStr::from_string(String::new()))];
+        block.merkle_root = compute_merkle_root(vec![block.transactions[0].hash().unwrap()]);
+        block
+    }
+
+    #[test]
+    fn migration_rebuilds_the_main_chain() {
+        let _data_dir = TempDataDir::new("blockchain-migration");
+        let genesis = Block::genesis_block().unwrap();
+        let main1 = child(&genesis, 1);
+        let main2 = child(&main1, 2);
+        let side1 = child(&genesis, 3);
+        let mut orphan = child(&main2, 4);
+        orphan.previous_hash = vec![7; 32];
+        orphan.height = 5;
+
+        let db = Blockchain::open().unwrap();
+        for (key, block) in [&genesis, &main1, &main2, &side1, &orphan].iter().enumerate() {
+            db.insert(vec![key as u8], block.send().unwrap()).unwrap();
+        }
+        db.flush().unwrap();
+        drop(db);
+        NextHash::insert_next_hash(genesis.hash().unwrap(), side1.hash().unwrap()).unwrap();
+        Utxos::connect_block(&side1).unwrap();
+
+        Blockchain::migrate_header_hashes().unwrap();
+        assert!(!Blockchain::has_block(&[0]).unwrap());
+        assert!(Blockchain::has_block(&side1.hash().unwrap()).unwrap());
+        assert_eq!(ChainIndex::tip().unwrap(), (2, main2.hash().unwrap()));
+        assert_eq!(ChainIndex::get_hash(1).unwrap(), main1.hash().unwrap());
+        assert_eq!(NextHash::get_next_hash(&genesis.hash().unwrap()).unwrap(), main1.hash().unwrap());
+        assert_eq!(NextHash::get_next_hash(&main1.hash().unwrap()).unwrap(), main2.hash().unwrap());
+        assert!(NextHash::get_next_hash(&main2.hash().unwrap()).is_err());
+        for (block, confirmed) in &[(&main1, true), (&main2, true), (&side1, false), (&orphan, false)] {
+            assert_eq!(Utxos::tx_exist(block.transactions[0].hash().unwrap()).unwrap(), *confirmed);
+        }
+    }
+
+    #[test]
+    fn tip_disconnected_stays_stored() {
+        let _data_dir = TempDataDir::new("blockchain-tip");
+        Blockchain::add_genesis_block().unwrap();
+        let genesis = Block::genesis_block().unwrap();
+        let main1 = child(&genesis, 1);
+        let hash = main1.hash().unwrap();
+        Blockchain::insert_block(hash.clone(), &main1).unwrap();
+        assert_eq!(ChainIndex::tip().unwrap().0, 0);
+
+        Blockchain::connect_tip(hash.clone(), &main1).unwrap();
+        assert_eq!(ChainIndex::tip().unwrap(), (1, hash.clone()));
+        assert_eq!(NextHash::get_next_hash(&genesis.hash().unwrap()).unwrap(), hash);
+
+        assert_eq!(Blockchain::disconnect_tip().unwrap().hash().unwrap(), hash);
+        assert_eq!(ChainIndex::tip().unwrap(), (0, genesis.hash().unwrap()));
+        assert!(NextHash::get_next_hash(&genesis.hash().unwrap()).is_err());
+        assert!(Blockchain::has_block(&hash).unwrap());
+        assert!(Blockchain::disconnect_tip().is_err());
+    }
 }
@@ -5,7 +5,17 @@ use utils::Error;
 use utils::hash;
 use utils::Size;
 
-#[derive(Debug, Clone)]
+// length bytes of buffer from offset, failing when the buffer is too short
+pub(crate) fn take(buffer: &[u8], offset: usize, length: usize) -> Result<&[u8], Error> {
+    buffer.get(offset..offset + length).ok_or_else(|| Error::ParseError("read out of the buffer".to_string()))
+}
+
+// what is left of buffer from offset, empty past its end
+pub(crate) fn rest(buffer: &[u8], offset: usize) -> &[u8] {
+    buffer.get(offset..).unwrap_or(&[])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Outpoint {
     pub hash: Vec<u8>,
     pub index: u32,
@@ -19,16 +29,16 @@ impl Outpoint {
         Ok(buffer)
     }
 
-    pub fn read(buffer: &[u8]) -> Outpoint {
-        let hash = buffer[0..32].to_vec();
-        let mut index = buffer[32..36].to_vec();
+    pub fn read(buffer: &[u8]) -> Result<Outpoint, Error> {
+        let hash = take(buffer, 0, 32)?.to_vec();
+        let mut index = take(buffer, 32, 4)?.to_vec();
         index.reverse();
-        let index = deserialize(&index).unwrap();
+        let index = deserialize(&index)?;
 
-        Outpoint {
+        Ok(Outpoint {
             hash,
             index
-        }
+        })
     }
 }
 impl Size for Outpoint {
@@ -52,15 +62,15 @@ impl TxIn {
         Ok(buffer)
     }
 
-    pub fn read(buffer: &[u8]) -> TxIn {
-        let previous_output = Outpoint::read(buffer);
-        let script = VarStr::new(&buffer[previous_output.size() as usize..].to_vec());
+    pub fn read(buffer: &[u8]) -> Result<TxIn, Error> {
+        let previous_output = Outpoint::read(buffer)?;
+        let script = VarStr::read(rest(buffer, previous_output.size() as usize))?;
 
-        TxIn {
+        Ok(TxIn {
             previous_output,
             script,
             shash:  Vec::new()
-        }
+        })
     }
 }
 impl Size for TxIn {
@@ -85,17 +95,17 @@ impl TxOut {
         buffer
     }
 
-    pub fn read(buffer: &[u8]) -> TxOut {
-        let mut value = buffer[0..8].to_vec();
+    pub fn read(buffer: &[u8]) -> Result<TxOut, Error> {
+        let mut value = take(buffer, 0, 8)?.to_vec();
         value.reverse();
-        let value = deserialize(&value).unwrap();
+        let value = deserialize(&value)?;
 
-        let script = VarStr::new(&buffer[8..].to_vec());
+        let script = VarStr::read(rest(buffer, 8))?;
 
-        TxOut {
+        Ok(TxOut {
             value,
             script
-        }
+        })
     }
 }
 impl Size for TxOut {
@@ -117,6 +127,35 @@ pub struct Transaction {
 
 impl Transaction {
 
+    pub fn coinbase(height: u32, value: u64, script: VarStr) -> Transaction {
+        let input = TxIn {
+            previous_output: Outpoint {
+                hash: vec![0; 32],
+                index: u32::MAX,
+            },
+            script: VarStr::from_string(height.to_string()),
+            shash: Vec::new(),
+        };
+        let output = TxOut {
+            value,
+            script,
+        };
+
+        Transaction {
+            version: 0,
+            flags_count: VarUint::from_u64(0),
+            flags: Vec::new(),
+            inputs_count: VarUint::from_u64(1),
+            inputs: vec![input],
+            outputs_count: VarUint::from_u64(1),
+            outputs: vec![output],
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output.hash == vec![0; 32]
+    }
+
     pub fn generic_shash_part(&mut self) -> Vec<u8> {
         let mut hash_vec = Vec::new();
 
@@ -184,41 +223,41 @@ impl Transaction {
         Ok(buffer)
     }
 
-    pub fn read(buffer: &[u8]) -> Transaction {
-        let mut version = buffer[0..4].to_vec();
+    pub fn read(buffer: &[u8]) -> Result<Transaction, Error> {
+        let mut version = take(buffer, 0, 4)?.to_vec();
         version.reverse();
-        let version = deserialize(&version).unwrap();
+        let version = deserialize(&version)?;
 
-        let flags_count = VarUint::new(&buffer[4..].to_vec());
+        let flags_count = VarUint::read(rest(buffer, 4))?;
         let mut offset : usize = 4 + flags_count.size() as usize;
 
         let mut flags = Vec::new();
         for _ in 0..flags_count.value {
-            let flag = VarStr::new(&buffer[offset..].to_vec());
+            let flag = VarStr::read(rest(buffer, offset))?;
             offset += flag.size() as usize;
             flags.push(flag);
         }
-        let inputs_count = VarUint::new(&buffer[offset..].to_vec());
+        let inputs_count = VarUint::read(rest(buffer, offset))?;
         offset += inputs_count.size() as usize;
 
         let mut inputs = Vec::new();
         for _ in 0..inputs_count.value {
-            let input = TxIn::read(&buffer[offset..].to_vec());
+            let input = TxIn::read(rest(buffer, offset))?;
             offset += input.size() as usize;
             inputs.push(input);
         }
 
-        let outputs_count = VarUint::new(&buffer[offset..].to_vec());
+        let outputs_count = VarUint::read(rest(buffer, offset))?;
         offset += outputs_count.size() as usize;
 
         let mut outputs = Vec::new();
         for _ in 0..outputs_count.value {
-            let output = TxOut::read(&buffer[offset..].to_vec());
+            let output = TxOut::read(rest(buffer, offset))?;
             offset += output.size() as usize;
             outputs.push(output);
         }
 
-        Transaction {
+        Ok(Transaction {
             version,
             flags_count,
             flags,
@@ -226,7 +265,7 @@ impl Transaction {
             inputs,
             outputs_count,
             outputs,
-        }
+        })
     }
 }
 impl Size for Transaction {
@@ -0,0 +1,155 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+use blockchain::block::Block;
+use blockchain::transaction::Outpoint;
+use utils::Error;
+use super::{ Mempool, MempoolEntry };
+
+impl Mempool {
+    // removes the transactions confirmed by the block, and those spending the same outputs
+    // with their descendants; returns the entries dropped because of a conflict
+    pub fn connect_block(&mut self, block: &Block) -> Result<Vec<MempoolEntry>, Error> {
+        let mut hashes = Vec::new();
+        for tx in &block.transactions {
+            hashes.push(tx.hash()?);
+        }
+        self.fees.block_connected(block.height, &hashes);
+
+        let mut conflicts = Vec::new();
+        for (tx, hash) in block.transactions.iter().zip(hashes).filter(|(tx, _)| !tx.is_coinbase()) {
+            self.orphans.remove(&hash);
+            if self.remove_entry(&hash).is_some() {
+                continue;
+            }
+            for input in &tx.inputs {
+                if let Some(spender) = self.outpoints.get(&input.previous_output).cloned() {
+                    conflicts.append(&mut self.remove_with_descendants(&spender));
+                }
+            }
+        }
+        Ok(conflicts)
+    }
+
+    // puts back the transactions of a block removed from the main chain, returns the hashes
+    // of those accepted again; the block must already be out of the utxo set
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<Vec<Vec<u8>>, Error> {
+        // the outputs of the coinbase don't exist anymore
+        for tx in block.transactions.iter().filter(|tx| tx.is_coinbase()) {
+            let hash = tx.hash()?;
+            for index in 0..tx.outputs.len() {
+                let outpoint = Outpoint {
+                    hash: hash.clone(),
+                    index: index as u32,
+                };
+                if let Some(spender) = self.outpoints.get(&outpoint).cloned() {
+                    self.remove_with_descendants(&spender);
+                }
+            }
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut readded = Vec::new();
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let hash = tx.hash()?;
+            if self.accept_tx(hash.clone(), tx, time).is_ok() {
+                self.link_children(&hash);
+                readded.push(hash);
+            }
+        }
+        self.limit_size();
+        readded.retain(|h| self.txs.contains_key(h));
+        self.track_fees(&readded);
+        Ok(readded)
+    }
+
+    // mempool transactions spending hash were accepted while it was confirmed
+    fn link_children(&mut self, hash: &[u8]) {
+        let outputs = self.txs[hash].tx.outputs.len();
+        for index in 0..outputs {
+            let outpoint = Outpoint {
+                hash: hash.to_vec(),
+                index: index as u32,
+            };
+            if let Some(child) = self.outpoints.get(&outpoint).cloned() {
+                self.txs.get_mut(&child).unwrap().parents.insert(hash.to_vec());
+                self.txs.get_mut(hash).unwrap().children.insert(child);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use blockchain::transaction::Transaction;
+    use model::VarStr;
+    use utils::datadir::TempDataDir;
+    use super::*;
+    use crate::testing::{ insert, tx };
+
+    fn block(height: u32, txs: Vec<Transaction>) -> Block {
+        let mut transactions = vec![Transaction::coinbase(height, 1, VarStr::from_string(String::new()))];
+        transactions.extend(txs);
+        Block {
+            version: 0,
+            flags: Vec::new(),
+            previous_hash: vec![0; 32],
+            merkle_root: vec![0; 32],
+            timestamp: 0,
+            height,
+            difficulty: vec![0; 32],
+            nonce: 0,
+            transactions,
+            hash: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn confirmed_tx_is_removed() {
+        let mut mempool = Mempool::new();
+        let confirmed = tx(&[(vec![1; 32], 0)], &[10]);
+        insert(&mut mempool, &confirmed, 1);
+
+        let conflicts = mempool.connect_block(&block(1, vec![confirmed])).unwrap();
+        assert!(conflicts.is_empty());
+        assert!(mempool.txs.is_empty());
+        assert!(mempool.outpoints.is_empty());
+        assert_eq!(mempool.usage(), 0);
+    }
+
+    #[test]
+    fn conflict_is_removed_with_descendants() {
+        let mut mempool = Mempool::new();
+        let spender = insert(&mut mempool, &tx(&[(vec![1; 32], 0)], &[10]), 1);
+        let child = insert(&mut mempool, &tx(&[(spender.clone(), 0)], &[9]), 1);
+        let unrelated = insert(&mut mempool, &tx(&[(vec![2; 32], 0)], &[10]), 1);
+
+        let double_spend = tx(&[(vec![1; 32], 0)], &[8]);
+        let conflicts = mempool.connect_block(&block(1, vec![double_spend])).unwrap();
+        let removed: HashSet<Vec<u8>> = conflicts.iter().map(|e| e.tx.hash().unwrap()).collect();
+        assert_eq!(removed, vec![spender, child].into_iter().collect());
+        assert_eq!(mempool.txs.keys().cloned().collect::<Vec<_>>(), vec![unrelated]);
+        assert_eq!(mempool.outpoints.len(), 1);
+    }
+
+    #[test]
+    fn disconnect_readds_txs_and_links() {
+        // accept_tx looks the txs up in the utxo set, keep it away from the real data dir
+        let _data_dir = TempDataDir::new("mempool-disconnect");
+        let mut mempool = Mempool::new();
+        let parent = insert(&mut mempool, &tx(&[(vec![1; 32], 0)], &[10_000]), 1);
+        let confirmed = tx(&[(parent.clone(), 0)], &[5_000]);
+        let hash = confirmed.hash().unwrap();
+        // accepted while confirmed spent its output
+        let child = insert(&mut mempool, &tx(&[(hash.clone(), 0)], &[4_000]), 1);
+        assert!(mempool.txs[&child].parents.is_empty());
+
+        let readded = mempool.disconnect_block(&block(1, vec![confirmed])).unwrap();
+        assert_eq!(readded, vec![hash.clone()]);
+        assert!(mempool.fees.is_tracked(&hash));
+        assert_eq!(mempool.txs.len(), 3);
+        assert!(mempool.txs[&hash].parents.contains(&parent));
+        assert!(mempool.txs[&hash].children.contains(&child));
+        assert!(mempool.txs[&child].parents.contains(&hash));
+        assert!(mempool.txs[&parent].children.contains(&hash));
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashSet;
+use blockchain::transaction::Transaction;
+
+// fee paid per 1000 bytes
+pub fn fee_rate(fee: u64, size: u64) -> u64 {
+    if size == 0 {
+        return 0
+    }
+    (u128::from(fee) * 1000 / u128::from(size)) as u64
+}
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx          : Transaction,
+    pub size        : u64,
+    pub fee         : u64,
+    // arrival time, in seconds since the epoch
+    pub time        : u64,
+    // unconfirmed transactions spent by this one
+    pub parents     : HashSet<Vec<u8>>,
+    // unconfirmed transactions spending this one
+    pub children    : HashSet<Vec<u8>>,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
@@ -0,0 +1,20 @@
+use tokio::sync::mpsc;
+use super::Mempool;
+
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    // a transaction was evicted by a replacement paying more fees
+    Replaced { replaced: Vec<u8>, by: Vec<u8> },
+}
+
+impl Mempool {
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<MempoolEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn notify(&mut self, event: MempoolEvent) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
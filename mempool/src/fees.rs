@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ BufReader, BufWriter, Read, Write };
+use std::path::PathBuf;
+use utils::data_dir;
+use blockchain::ChainIndex;
+use utils::Error;
+use super::persist::read_u64;
+
+// estimates are given for confirmation within 1 to MAX_CONFIRMATION_TARGET blocks
+pub const MAX_CONFIRMATION_TARGET   : usize = 25;
+// older data points weight less, halving every ~350 blocks
+const DECAY                         : f64   = 0.998;
+const SUCCESS_THRESHOLD             : f64   = 0.85;
+const SUFFICIENT_DATA               : f64   = 2.0;
+const MIN_BUCKET_FEE_RATE           : f64   = 1000.0;
+const MAX_BUCKET_FEE_RATE           : f64   = 10_000_000.0;
+const BUCKET_SPACING                : f64   = 1.5;
+
+fn path() -> PathBuf {
+    let mut path = data_dir();
+    path.push("fee_estimates.dat");
+    path
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    // lowest fee rate, per 1000 bytes, of the transactions in this bucket
+    fee_rate    : u64,
+    total       : f64,
+    // confirmed[i] counts the transactions confirmed within i + 1 blocks
+    confirmed   : Vec<f64>,
+}
+
+// tracks how many blocks mempool transactions needed to confirm, by fee rate
+#[derive(Debug)]
+pub struct FeeEstimator {
+    buckets     : Vec<Bucket>,
+    // tracked mempool transaction, with the height it entered at and its bucket
+    tracked     : HashMap<Vec<u8>, (u32, usize)>,
+    best_height : u32,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        let mut buckets = vec![0];
+        let mut rate = MIN_BUCKET_FEE_RATE;
+        while rate <= MAX_BUCKET_FEE_RATE {
+            buckets.push(rate as u64);
+            rate *= BUCKET_SPACING;
+        }
+
+        Self {
+            buckets: buckets.into_iter().map(|fee_rate| Bucket {
+                fee_rate,
+                total: 0.0,
+                confirmed: vec![0.0; MAX_CONFIRMATION_TARGET],
+            }).collect(),
+            tracked: HashMap::new(),
+            best_height: 0,
+        }
+    }
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn best_height(&self) -> u32 {
+        self.best_height
+    }
+
+    fn bucket(&self, fee_rate: u64) -> usize {
+        self.buckets.iter().rposition(|b| b.fee_rate <= fee_rate).unwrap_or(0)
+    }
+
+    pub fn track(&mut self, hash: Vec<u8>, fee_rate: u64) {
+        let bucket = self.bucket(fee_rate);
+        self.tracked.insert(hash, (self.best_height, bucket));
+    }
+
+    pub fn is_tracked(&self, hash: &[u8]) -> bool {
+        self.tracked.contains_key(hash)
+    }
+
+    // the transaction left the mempool without being confirmed
+    pub fn untrack(&mut self, hash: &[u8]) {
+        self.tracked.remove(hash);
+    }
+
+    pub fn block_connected(&mut self, height: u32, hashes: &[Vec<u8>]) {
+        if height <= self.best_height {
+            // reorganisation, the confirmations were already counted
+            for hash in hashes {
+                self.tracked.remove(hash);
+            }
+            return
+        }
+        self.best_height = height;
+
+        for bucket in self.buckets.iter_mut() {
+            bucket.total *= DECAY;
+            for c in bucket.confirmed.iter_mut() {
+                *c *= DECAY;
+            }
+        }
+
+        for hash in hashes {
+            if let Some((entry_height, bucket)) = self.tracked.remove(hash) {
+                let blocks = std::cmp::max(1, height.saturating_sub(entry_height)) as usize;
+                let bucket = &mut self.buckets[bucket];
+                bucket.total += 1.0;
+                for c in bucket.confirmed.iter_mut().skip(blocks - 1) {
+                    *c += 1.0;
+                }
+            }
+        }
+    }
+
+    // lowest fee rate, per 1000 bytes, for which transactions at this rate and above
+    // were confirmed within target blocks often enough
+    pub fn estimate(&self, target: usize) -> Option<u64> {
+        if target == 0 || target > MAX_CONFIRMATION_TARGET {
+            return None
+        }
+
+        let mut estimate = None;
+        let (mut total, mut confirmed) = (0.0, 0.0);
+        for bucket in self.buckets.iter().rev() {
+            total += bucket.total;
+            confirmed += bucket.confirmed[target - 1];
+            if total < SUFFICIENT_DATA {
+                continue;
+            }
+            if confirmed / total < SUCCESS_THRESHOLD {
+                break;
+            }
+            estimate = Some(bucket.fee_rate);
+            total = 0.0;
+            confirmed = 0.0;
+        }
+        estimate
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = path();
+        let mut tmp = path.clone();
+        tmp.set_extension("dat.new");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writer.write_all(&self.best_height.to_be_bytes())?;
+            writer.write_all(&(self.buckets.len() as u64).to_be_bytes())?;
+            for bucket in &self.buckets {
+                writer.write_all(&bucket.fee_rate.to_be_bytes())?;
+                writer.write_all(&bucket.total.to_bits().to_be_bytes())?;
+                for c in &bucket.confirmed {
+                    writer.write_all(&c.to_bits().to_be_bytes())?;
+                }
+            }
+            writer.flush()?;
+        }
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    // the saved data with best_height set to the current tip, the node may have
+    // connected blocks since it was saved
+    pub fn load() -> Result<FeeEstimator, Error> {
+        let mut estimator = FeeEstimator::read()?;
+        if let Ok((height, _)) = ChainIndex::tip() {
+            estimator.best_height = height;
+        }
+        Ok(estimator)
+    }
+
+    fn read() -> Result<FeeEstimator, Error> {
+        let mut estimator = FeeEstimator::new();
+        let file = match File::open(path()) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(estimator),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+
+        let mut height = [0; 4];
+        reader.read_exact(&mut height)?;
+        let count = read_u64(&mut reader)? as usize;
+        let mut buckets = Vec::with_capacity(count);
+        for _ in 0..count {
+            let fee_rate = read_u64(&mut reader)?;
+            let total = f64::from_bits(read_u64(&mut reader)?);
+            let mut confirmed = Vec::with_capacity(MAX_CONFIRMATION_TARGET);
+            for _ in 0..MAX_CONFIRMATION_TARGET {
+                confirmed.push(f64::from_bits(read_u64(&mut reader)?));
+            }
+            buckets.push(Bucket { fee_rate, total, confirmed });
+        }
+
+        // the bucket layout changed, the old data can't be used
+        if buckets.iter().map(|b| b.fee_rate).ne(estimator.buckets.iter().map(|b| b.fee_rate)) {
+            return Ok(estimator)
+        }
+        estimator.buckets = buckets;
+        estimator.best_height = u32::from_be_bytes(height);
+        Ok(estimator)
+    }
+}
@@ -1,24 +1,52 @@
-use std::collections::HashMap;
+pub mod connect;
+pub mod entry;
+pub mod events;
+pub mod fees;
+pub mod limits;
+pub mod orphans;
+pub mod package;
+pub mod persist;
+pub mod rbf;
+pub mod reject;
+pub mod template;
+#[cfg(test)]
+mod testing;
+
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+use tokio::sync::mpsc;
+use blockchain::scripts::verify_script;
 use blockchain::transaction::*;
 use blockchain::Utxos;
 use utils::Error;
+use utils::Size;
+
+pub use entry::MempoolEntry;
+pub use events::MempoolEvent;
+pub use fees::FeeEstimator;
+pub use limits::DEFAULT_MAX_SIZE;
+pub use orphans::OrphanPool;
+pub use package::PackageStats;
+pub use reject::RejectReason;
+pub use template::BlockTemplate;
 
 #[derive(Debug)]
 pub struct Mempool {
-    pub txs                 : HashMap<Vec<u8>, Transaction>,
-    pub orphans             : HashMap<Vec<u8>, Transaction>,
-    pub outpoints           : HashMap<Vec<u8>, Outpoint>,
-    pub orphans_outpoints   : HashMap<Vec<u8>, Outpoint>
+    pub txs                 : HashMap<Vec<u8>, MempoolEntry>,
+    pub orphans             : OrphanPool,
+    // outpoints spent by mempool transactions, with the hash of the spender
+    pub outpoints           : HashMap<Outpoint, Vec<u8>>,
+    pub fees                : FeeEstimator,
+        usage               : u64,
+        max_size            : u64,
+        rolling_fee_rate    : u64,
+        last_rolling_update : Instant,
+        subscribers         : Vec<mpsc::UnboundedSender<MempoolEvent>>,
 }
 
 impl Default for Mempool {
     fn default() -> Self {
-        Self {
-            txs                 : HashMap::new(),
-            orphans             : HashMap::new(),
-            outpoints           : HashMap::new(),
-            orphans_outpoints   : HashMap::new(),
-        }
+        Self::with_max_size(DEFAULT_MAX_SIZE)
     }
 }
 
@@ -27,32 +55,262 @@ impl Mempool {
         Self::default()
     }
 
-    // tx is not in self.txs and not in self.orphans
-    pub fn add_tx(&mut self, tx: &Transaction) -> Result<(), Error> {
-        let mut utxos = Vec::new();
+    pub fn with_max_size(max_size: u64) -> Self {
+        Self {
+            txs                 : HashMap::new(),
+            orphans             : OrphanPool::new(),
+            outpoints           : HashMap::new(),
+            fees                : FeeEstimator::new(),
+            usage               : 0,
+            max_size,
+            rolling_fee_rate    : 0,
+            last_rolling_update : Instant::now(),
+            subscribers         : Vec::new(),
+        }
+    }
+
+    // estimated memory used by the accepted transactions
+    pub fn usage(&self) -> u64 {
+        self.usage
+    }
+
+    // on success, returns the hash of tx followed by those of the orphans it unlocked
+    pub fn add_tx(&mut self, tx: &Transaction) -> Result<Vec<Vec<u8>>, RejectReason> {
+        let hash = tx.hash()?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        match self.accept_tx(hash.clone(), tx, time) {
+            Ok(()) => (),
+            Err(RejectReason::MissingInputs(parents)) => {
+                if !self.orphans.add(hash, tx.clone(), parents.clone()) {
+                    return Err(RejectReason::OrphanTooLarge)
+                }
+                return Err(RejectReason::MissingInputs(parents))
+            },
+            Err(e) => return Err(e),
+        }
+
+        let mut accepted = vec![hash.clone()];
+        let mut queue = VecDeque::new();
+        queue.push_back(hash.clone());
+        while let Some(parent) = queue.pop_front() {
+            for child in self.orphans.children(&parent) {
+                let orphan = match self.orphans.remove(&child) {
+                    Some(orphan) => orphan,
+                    None => continue,
+                };
+                match self.accept_tx(child.clone(), &orphan, time) {
+                    Ok(()) => {
+                        accepted.push(child.clone());
+                        queue.push_back(child);
+                    },
+                    Err(RejectReason::MissingInputs(parents)) => {
+                        self.orphans.add(child, orphan, parents);
+                    },
+                    Err(_) => (),
+                }
+            }
+        }
+
+        self.limit_size();
+        if !self.txs.contains_key(&hash) {
+            return Err(RejectReason::MempoolFull)
+        }
+        accepted.retain(|h| self.txs.contains_key(h));
+        self.track_fees(&accepted);
+        Ok(accepted)
+    }
+
+    // starts counting the blocks until the transactions get confirmed
+    fn track_fees(&mut self, hashes: &[Vec<u8>]) {
+        for h in hashes {
+            let fee_rate = self.txs[h].fee_rate();
+            self.fees.track(h.clone(), fee_rate);
+        }
+    }
+
+    fn accept_tx(&mut self, hash: Vec<u8>, tx: &Transaction, time: u64) -> Result<(), RejectReason> {
+        if self.txs.contains_key(&hash) {
+            return Err(RejectReason::AlreadyKnown)
+        }
+        if Utxos::tx_exist(hash.clone())? {
+            return Err(RejectReason::AlreadyConfirmed)
+        }
+        if !tx.is_sane() {
+            return Err(RejectReason::NotSane)
+        }
+        if tx.is_coinbase() {
+            return Err(RejectReason::Coinbase)
+        }
+
+        let mut spent = HashSet::new();
+        let mut conflicts = HashSet::new();
+        let mut missing = Vec::new();
+        let mut input_sum: u64 = 0;
         for input in &tx.inputs {
-            if !self.txs.contains_key(&input.previous_output.hash) {
-                self.orphans.insert(tx.hash()?, tx.clone());
-                self.orphans_outpoints.insert(input.previous_output.hash.clone(), input.previous_output.clone());
+            let outpoint = &input.previous_output;
+            if !spent.insert(outpoint.clone()) {
+                return Err(RejectReason::DuplicateInput(outpoint.clone()))
+            }
+            if let Some(spender) = self.outpoints.get(outpoint) {
+                if !rbf::is_replaceable(tx) {
+                    return Err(RejectReason::Conflict(spender.clone()))
+                }
+                conflicts.insert(spender.clone());
             }
-            let mut utxo = Utxos::get_utxos(input.previous_output.hash.clone())?;
-            if utxo.is_empty() {
-                return Err(Error::TxNotValid)
-            } else {
-                utxos.append(&mut utxo);
+            match self.get_output(outpoint)? {
+                Some(txo) => {
+                    input_sum = input_sum.checked_add(txo.value).ok_or(RejectReason::NotSane)?;
+                },
+                None => {
+                    if self.txs.contains_key(&outpoint.hash) || Utxos::tx_exist(outpoint.hash.clone())? {
+                        return Err(RejectReason::InvalidInput(outpoint.clone()))
+                    }
+                    if !missing.contains(&outpoint.hash) {
+                        missing.push(outpoint.hash.clone());
+                    }
+                },
             }
         }
-        //valid tx
-        let txto = TxTxo::new(tx, utxos);
-        if !txto.is_valid() {
+        if !missing.is_empty() {
+            return Err(RejectReason::MissingInputs(missing))
         }
 
-        self.txs.insert(tx.hash()?, tx.clone());
-        // check if TxOut of tx are in orphans
+        for input in &tx.inputs {
+            if !verify_script(input.script.value.as_bytes()) {
+                return Err(RejectReason::ScriptFailed)
+            }
+        }
+
+        let mut output_sum: u64 = 0;
+        for output in &tx.outputs {
+            output_sum = output_sum.checked_add(output.value).ok_or(RejectReason::NotSane)?;
+        }
+        if output_sum > input_sum {
+            return Err(RejectReason::NegativeFee)
+        }
+        let fee = input_sum - output_sum;
+        let size = tx.size();
+        let min_fee_rate = self.min_fee_rate();
+        if entry::fee_rate(fee, size) < min_fee_rate {
+            return Err(RejectReason::InsufficientFee(min_fee_rate))
+        }
+
+        let parents: HashSet<Vec<u8>> = self.parents(tx).into_iter().collect();
+        let replaced = if conflicts.is_empty() {
+            HashSet::new()
+        } else {
+            self.check_replacement(tx, fee, size, &conflicts)?
+        };
+        if !self.check_chain_limits(&parents, size) {
+            return Err(RejectReason::TooLongChain)
+        }
+
+        for replaced in replaced {
+            self.remove_entry(&replaced);
+            self.notify(MempoolEvent::Replaced {
+                replaced,
+                by: hash.clone(),
+            });
+        }
+
+        for parent in &parents {
+            self.txs.get_mut(parent).unwrap().children.insert(hash.clone());
+        }
+        for input in &tx.inputs {
+            self.outpoints.insert(input.previous_output.clone(), hash.clone());
+        }
+        let entry = MempoolEntry {
+            tx: tx.clone(),
+            size,
+            fee,
+            time,
+            parents,
+            children: HashSet::new(),
+        };
+        self.usage += limits::entry_usage(&entry);
+        self.txs.insert(hash, entry);
         Ok(())
     }
 
+    // unlinks a single entry, its children are left pointing to a missing parent
+    fn remove_entry(&mut self, hash: &[u8]) -> Option<MempoolEntry> {
+        let entry = self.txs.remove(hash)?;
+        for parent in &entry.parents {
+            if let Some(p) = self.txs.get_mut(parent) {
+                p.children.remove(hash);
+            }
+        }
+        for child in &entry.children {
+            if let Some(c) = self.txs.get_mut(child) {
+                c.parents.remove(hash);
+            }
+        }
+        for input in &entry.tx.inputs {
+            self.outpoints.remove(&input.previous_output);
+        }
+        self.usage -= limits::entry_usage(&entry);
+        self.fees.untrack(hash);
+        Some(entry)
+    }
+
+    // hash and every unconfirmed transaction depending on it
+    pub fn descendants(&self, hash: &[u8]) -> HashSet<Vec<u8>> {
+        let mut descendants = HashSet::new();
+        let mut queue = vec![hash.to_vec()];
+        while let Some(h) = queue.pop() {
+            if let Some(entry) = self.txs.get(&h) {
+                if descendants.insert(h) {
+                    queue.extend(entry.children.iter().cloned());
+                }
+            }
+        }
+        descendants
+    }
+
+    pub fn remove_with_descendants(&mut self, hash: &[u8]) -> Vec<MempoolEntry> {
+        let mut removed = Vec::new();
+        for h in self.descendants(hash) {
+            if let Some(entry) = self.remove_entry(&h) {
+                removed.push(entry);
+            }
+        }
+        removed
+    }
+
     pub fn contains_tx(&self, hash: Vec<u8>) -> bool {
-        self.txs.contains_key(&hash) || self.orphans.contains_key(&hash)
+        self.txs.contains_key(&hash) || self.orphans.contains(&hash)
+    }
+
+    // output spent by an input, either unconfirmed in the mempool or in the utxo set
+    pub fn get_output(&self, outpoint: &Outpoint) -> Result<Option<TxOut>, Error> {
+        if let Some(parent) = self.txs.get(&outpoint.hash) {
+            return Ok(parent.tx.outputs.get(outpoint.index as usize).cloned())
+        }
+        Utxos::get_utxo(outpoint)
+    }
+
+    pub fn fee(&self, tx: &Transaction) -> Result<u64, Error> {
+        let mut input_sum = 0;
+        for input in &tx.inputs {
+            match self.get_output(&input.previous_output)? {
+                Some(txo) => input_sum += txo.value,
+                None => return Err(Error::TxNotValid),
+            }
+        }
+        let output_sum = tx.outputs.iter().fold(0, |acc, txo| acc + txo.value);
+
+        input_sum.checked_sub(output_sum).ok_or(Error::TxNotValid)
+    }
+
+    // hashes of the unconfirmed transactions tx depends on
+    pub fn parents(&self, tx: &Transaction) -> Vec<Vec<u8>> {
+        let mut parents: Vec<Vec<u8>> = Vec::new();
+        for input in &tx.inputs {
+            let hash = &input.previous_output.hash;
+            if self.txs.contains_key(hash) && !parents.contains(hash) {
+                parents.push(hash.clone());
+            }
+        }
+        parents
     }
 }
@@ -0,0 +1,146 @@
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, HashMap, HashSet };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use super::entry::MempoolEntry;
+use super::Mempool;
+
+pub const DEFAULT_MAX_SIZE      : u64       = 300_000_000;
+// transactions unconfirmed for two weeks are dropped
+pub const MEMPOOL_EXPIRY        : u64       = 14 * 24 * 60 * 60;
+// fee rates are expressed per 1000 bytes
+pub const MIN_RELAY_FEE_RATE    : u64       = 0;
+pub const INCREMENTAL_FEE_RATE  : u64       = 1000;
+const ROLLING_FEE_HALFLIFE      : Duration  = Duration::from_secs(12 * 60 * 60);
+// bookkeeping kept in memory for each entry besides the transaction itself
+const ENTRY_OVERHEAD            : u64       = 256;
+
+pub fn entry_usage(entry: &MempoolEntry) -> u64 {
+    2 * entry.size + ENTRY_OVERHEAD
+}
+
+impl Mempool {
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    // minimum fee rate to enter the mempool, raised after evictions then decaying back
+    pub fn min_fee_rate(&mut self) -> u64 {
+        if self.rolling_fee_rate == 0 {
+            return MIN_RELAY_FEE_RATE
+        }
+
+        let mut halflife = ROLLING_FEE_HALFLIFE.as_secs_f64();
+        if self.usage < self.max_size / 4 {
+            halflife /= 4.0;
+        } else if self.usage < self.max_size / 2 {
+            halflife /= 2.0;
+        }
+        let elapsed = self.last_rolling_update.elapsed().as_secs_f64();
+        let rate = self.rolling_fee_rate as f64 / 2f64.powf(elapsed / halflife);
+        self.rolling_fee_rate = rate as u64;
+        self.last_rolling_update = Instant::now();
+        if self.rolling_fee_rate < INCREMENTAL_FEE_RATE / 2 {
+            self.rolling_fee_rate = 0;
+        }
+
+        std::cmp::max(self.rolling_fee_rate, MIN_RELAY_FEE_RATE)
+    }
+
+    fn bump_min_fee_rate(&mut self, rate: u64) {
+        self.min_fee_rate();
+        if rate > self.rolling_fee_rate {
+            self.rolling_fee_rate = rate;
+            self.last_rolling_update = Instant::now();
+        }
+    }
+
+    // drops transactions which stayed unconfirmed for too long, with their descendants
+    pub fn expire(&mut self, now: u64) -> Vec<MempoolEntry> {
+        let expired: Vec<Vec<u8>> = self.txs.iter()
+            .filter(|(_, e)| e.time + MEMPOOL_EXPIRY <= now)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        let mut removed = Vec::new();
+        for hash in expired {
+            removed.append(&mut self.remove_with_descendants(&hash));
+        }
+        removed
+    }
+
+    // evicts the packages (a transaction and its descendants) paying the lowest fee rate
+    // until the mempool fits in max_size
+    pub fn limit_size(&mut self) -> Vec<MempoolEntry> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut removed = self.expire(now);
+
+        if self.usage <= self.max_size {
+            return removed
+        }
+
+        // descendant scores are computed once, then only those of the ancestors of evicted
+        // transactions change; outdated entries of the heap are skipped
+        let mut rates = HashMap::new();
+        let mut scores = BinaryHeap::new();
+        for hash in self.txs.keys() {
+            let rate = self.descendant_stats(hash).unwrap().fee_rate();
+            rates.insert(hash.clone(), rate);
+            scores.push(Reverse((rate, hash.clone())));
+        }
+
+        while self.usage > self.max_size {
+            let (rate, hash) = match scores.pop() {
+                Some(Reverse(score)) => score,
+                None => break,
+            };
+            if rates.get(&hash) != Some(&rate) || !self.txs.contains_key(&hash) {
+                continue;
+            }
+
+            let package = self.descendants(&hash);
+            let mut ancestors = HashSet::new();
+            for h in &package {
+                ancestors.extend(self.ancestors(h));
+            }
+            self.bump_min_fee_rate(rate + INCREMENTAL_FEE_RATE);
+            removed.append(&mut self.remove_with_descendants(&hash));
+
+            for h in ancestors {
+                match self.descendant_stats(&h) {
+                    Some(stats) => {
+                        let rate = stats.fee_rate();
+                        rates.insert(h.clone(), rate);
+                        scores.push(Reverse((rate, h)));
+                    },
+                    None => {
+                        rates.remove(&h);
+                    },
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ insert, tx };
+
+    #[test]
+    fn evictions_update_ancestor_scores() {
+        let mut mempool = Mempool::new();
+        let parent = insert(&mut mempool, &tx(&[(vec![1; 32], 0)], &[10, 10]), 0);
+        let child = insert(&mut mempool, &tx(&[(parent.clone(), 0)], &[9]), 10_000);
+        let cheap = insert(&mut mempool, &tx(&[(parent.clone(), 1)], &[9]), 100);
+        let other = insert(&mut mempool, &tx(&[(vec![2; 32], 0)], &[10]), 4_000);
+        mempool.max_size = entry_usage(&mempool.txs[&parent]) + entry_usage(&mempool.txs[&child]);
+
+        // once cheap is gone, the package of parent pays more than other
+        let removed: Vec<Vec<u8>> = mempool.limit_size().iter().map(|e| e.tx.hash().unwrap()).collect();
+        assert_eq!(removed, vec![cheap, other]);
+        assert!(mempool.txs.contains_key(&parent));
+        assert!(mempool.txs.contains_key(&child));
+        assert!(mempool.usage() <= mempool.max_size());
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::{ HashMap, HashSet };
+use std::time::{ Duration, Instant };
+use rand::seq::IteratorRandom;
+use blockchain::transaction::Transaction;
+use utils::Size;
+
+pub const MAX_ORPHANS       : usize     = 100;
+pub const MAX_ORPHAN_SIZE   : u64       = 100_000;
+pub const ORPHAN_EXPIRY     : Duration  = Duration::from_secs(20 * 60);
+const ORPHAN_SWEEP          : Duration  = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct Orphan {
+    tx      : Transaction,
+    parents : Vec<Vec<u8>>,
+    expires : Instant,
+}
+
+// transactions waiting for their parents, indexed by the hash of each missing parent
+#[derive(Debug)]
+pub struct OrphanPool {
+    orphans     : HashMap<Vec<u8>, Orphan>,
+    by_parent   : HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    next_sweep  : Instant,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self {
+            orphans     : HashMap::new(),
+            by_parent   : HashMap::new(),
+            next_sweep  : Instant::now() + ORPHAN_SWEEP,
+        }
+    }
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    // returns false when the orphan is too big to be kept
+    pub fn add(&mut self, hash: Vec<u8>, tx: Transaction, parents: Vec<Vec<u8>>) -> bool {
+        if self.orphans.contains_key(&hash) {
+            return true
+        }
+        if tx.size() > MAX_ORPHAN_SIZE {
+            return false
+        }
+
+        self.expire();
+        for parent in &parents {
+            self.by_parent.entry(parent.clone()).or_default().insert(hash.clone());
+        }
+        self.orphans.insert(hash, Orphan {
+            tx,
+            parents,
+            expires: Instant::now() + ORPHAN_EXPIRY,
+        });
+
+        let mut rng = rand::thread_rng();
+        while self.orphans.len() > MAX_ORPHANS {
+            let evicted = self.orphans.keys().choose(&mut rng).unwrap().clone();
+            self.remove(&evicted);
+        }
+        true
+    }
+
+    pub fn remove(&mut self, hash: &[u8]) -> Option<Transaction> {
+        let orphan = self.orphans.remove(hash)?;
+        for parent in &orphan.parents {
+            if let Some(children) = self.by_parent.get_mut(parent) {
+                children.remove(hash);
+                if children.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        Some(orphan.tx)
+    }
+
+    // orphans spending an output of the given transaction
+    pub fn children(&self, parent: &[u8]) -> Vec<Vec<u8>> {
+        match self.by_parent.get(parent) {
+            Some(children) => children.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        if now < self.next_sweep {
+            return
+        }
+        let expired: Vec<Vec<u8>> = self.orphans.iter()
+            .filter(|(_, o)| o.expires <= now)
+            .map(|(h, _)| h.clone())
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+        self.next_sweep = now + ORPHAN_SWEEP;
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use super::entry::fee_rate;
+use super::Mempool;
+
+// limits on unconfirmed chains, counting the transaction itself
+pub const MAX_ANCESTORS         : u64 = 25;
+pub const MAX_ANCESTOR_SIZE     : u64 = 101_000;
+pub const MAX_DESCENDANTS       : u64 = 25;
+pub const MAX_DESCENDANT_SIZE   : u64 = 101_000;
+
+// aggregate of a set of mempool entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PackageStats {
+    pub count   : u64,
+    pub size    : u64,
+    pub fees    : u64,
+}
+
+impl PackageStats {
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fees, self.size)
+    }
+}
+
+impl Mempool {
+    // hash and every unconfirmed transaction it depends on
+    pub fn ancestors(&self, hash: &[u8]) -> HashSet<Vec<u8>> {
+        let mut ancestors = HashSet::new();
+        let mut queue = vec![hash.to_vec()];
+        while let Some(h) = queue.pop() {
+            if let Some(entry) = self.txs.get(&h) {
+                if ancestors.insert(h) {
+                    queue.extend(entry.parents.iter().cloned());
+                }
+            }
+        }
+        ancestors
+    }
+
+    pub fn package_stats<'a, I>(&self, hashes: I) -> PackageStats
+        where I: IntoIterator<Item = &'a Vec<u8>> {
+        let mut stats = PackageStats::default();
+        for hash in hashes {
+            if let Some(entry) = self.txs.get(hash) {
+                stats.count += 1;
+                stats.size += entry.size;
+                stats.fees += entry.fee;
+            }
+        }
+        stats
+    }
+
+    pub fn ancestor_stats(&self, hash: &[u8]) -> Option<PackageStats> {
+        if !self.txs.contains_key(hash) {
+            return None
+        }
+        Some(self.package_stats(&self.ancestors(hash)))
+    }
+
+    pub fn descendant_stats(&self, hash: &[u8]) -> Option<PackageStats> {
+        if !self.txs.contains_key(hash) {
+            return None
+        }
+        Some(self.package_stats(&self.descendants(hash)))
+    }
+
+    // checks a transaction of the given size spending parents would not make a chain too long
+    pub(crate) fn check_chain_limits(&self, parents: &HashSet<Vec<u8>>, size: u64) -> bool {
+        let mut ancestors = HashSet::new();
+        for parent in parents {
+            ancestors.extend(self.ancestors(parent));
+        }
+        let stats = self.package_stats(&ancestors);
+        if stats.count + 1 > MAX_ANCESTORS || stats.size + size > MAX_ANCESTOR_SIZE {
+            return false
+        }
+
+        ancestors.iter().all(|a| {
+            let descendants = self.package_stats(&self.descendants(a));
+            descendants.count < MAX_DESCENDANTS && descendants.size + size <= MAX_DESCENDANT_SIZE
+        })
+    }
+}
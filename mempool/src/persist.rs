@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+use std::fs::{ self, File };
+use std::io::{ BufReader, BufWriter, Read, Write };
+use std::path::PathBuf;
+use utils::data_dir;
+use blockchain::transaction::Transaction;
+use utils::Error;
+use super::fees::FeeEstimator;
+use super::Mempool;
+
+fn path() -> PathBuf {
+    let mut path = data_dir();
+    path.push("mempool.dat");
+    path
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_be_bytes(buffer))
+}
+
+impl Mempool {
+    // writes every transaction, parents first, as its arrival time, length and encoding
+    pub fn dump(&self) -> Result<usize, Error> {
+        let mut ordered = Vec::new();
+        let mut written: HashSet<&Vec<u8>> = HashSet::new();
+        loop {
+            let before = ordered.len();
+            for (hash, entry) in &self.txs {
+                if !written.contains(hash) && entry.parents.iter().all(|p| written.contains(p)) {
+                    written.insert(hash);
+                    ordered.push(entry);
+                }
+            }
+            if ordered.len() == before {
+                break;
+            }
+        }
+
+        let path = path();
+        let mut tmp = path.clone();
+        tmp.set_extension("dat.new");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writer.write_all(&(ordered.len() as u64).to_be_bytes())?;
+            for entry in &ordered {
+                let tx = entry.tx.send()?;
+                writer.write_all(&entry.time.to_be_bytes())?;
+                writer.write_all(&(tx.len() as u64).to_be_bytes())?;
+                writer.write_all(&tx)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(tmp, path)?;
+        self.fees.save()?;
+        Ok(ordered.len())
+    }
+
+    // validates the dumped transactions again against the current chain state,
+    // returns how many of them were accepted
+    pub fn load(&mut self) -> Result<usize, Error> {
+        self.fees = FeeEstimator::load()?;
+        let file = match File::open(path()) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+
+        let count = read_u64(&mut reader)?;
+        let mut accepted = Vec::new();
+        for _ in 0..count {
+            let time = read_u64(&mut reader)?;
+            let length = read_u64(&mut reader)?;
+            let mut buffer = Vec::new();
+            (&mut reader).take(length).read_to_end(&mut buffer)?;
+            if buffer.len() as u64 != length {
+                return Err(Error::ParseError("Truncated mempool file".to_string()))
+            }
+
+            let tx = Transaction::read(&buffer)?;
+            let hash = tx.hash()?;
+            if self.accept_tx(hash.clone(), &tx, time).is_ok() {
+                accepted.push(hash);
+            }
+        }
+        let loaded = accepted.len();
+        self.limit_size();
+        accepted.retain(|h| self.txs.contains_key(h));
+        self.track_fees(&accepted);
+        Ok(loaded)
+    }
+}
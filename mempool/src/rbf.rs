@@ -0,0 +1,47 @@
+use std::collections::HashSet;
+use blockchain::transaction::Transaction;
+use super::entry::fee_rate;
+use super::limits::INCREMENTAL_FEE_RATE;
+use super::{ Mempool, RejectReason };
+
+// transactions carrying this flag may replace conflicting mempool entries
+pub const REPLACEABLE_FLAG              : &str  = "rbf";
+pub const MAX_REPLACEMENT_EVICTIONS     : usize = 100;
+
+pub fn is_replaceable(tx: &Transaction) -> bool {
+    tx.flags.iter().any(|f| f.value == REPLACEABLE_FLAG)
+}
+
+impl Mempool {
+    // checks that tx may evict the conflicting entries, returns every hash it would evict
+    pub(crate) fn check_replacement(&self, tx: &Transaction, fee: u64, size: u64, conflicts: &HashSet<Vec<u8>>) -> Result<HashSet<Vec<u8>>, RejectReason> {
+        let mut evicted = HashSet::new();
+        for conflict in conflicts {
+            evicted.extend(self.descendants(conflict));
+            if evicted.len() > MAX_REPLACEMENT_EVICTIONS {
+                return Err(RejectReason::TooManyReplacements)
+            }
+        }
+
+        for input in &tx.inputs {
+            if evicted.contains(&input.previous_output.hash) {
+                return Err(RejectReason::SpendsReplaced)
+            }
+        }
+
+        let new_rate = fee_rate(fee, size);
+        let mut evicted_fees = 0;
+        for hash in &evicted {
+            let entry = &self.txs[hash];
+            evicted_fees += entry.fee;
+            if conflicts.contains(hash) && entry.fee_rate() >= new_rate {
+                return Err(RejectReason::InsufficientReplacementFee)
+            }
+        }
+        // the replacement also pays for relaying itself
+        if fee <= evicted_fees || fee - evicted_fees < INCREMENTAL_FEE_RATE * size / 1000 {
+            return Err(RejectReason::InsufficientReplacementFee)
+        }
+        Ok(evicted)
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt::Display;
+use blockchain::transaction::Outpoint;
+use utils::Error;
+
+#[derive(Debug)]
+pub enum RejectReason {
+    AlreadyKnown,
+    AlreadyConfirmed,
+    NotSane,
+    Coinbase,
+    DuplicateInput(Outpoint),
+    // hashes of the parents that are neither in the mempool nor in the utxo set
+    MissingInputs(Vec<Vec<u8>>),
+    OrphanTooLarge,
+    // the parent is known but does not have this output (anymore)
+    InvalidInput(Outpoint),
+    // hash of the mempool transaction already spending one of the inputs
+    Conflict(Vec<u8>),
+    // too many unconfirmed ancestors or descendants
+    TooLongChain,
+    TooManyReplacements,
+    SpendsReplaced,
+    InsufficientReplacementFee,
+    ScriptFailed,
+    NegativeFee,
+    // minimum fee rate, per 1000 bytes, required to enter the mempool
+    InsufficientFee(u64),
+    MempoolFull,
+    Storage(Error),
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::AlreadyKnown      => write!(f, "Transaction already in mempool"),
+            RejectReason::AlreadyConfirmed  => write!(f, "Transaction already confirmed"),
+            RejectReason::NotSane           => write!(f, "Transaction is not sane"),
+            RejectReason::Coinbase          => write!(f, "Coinbase transaction outside of a block"),
+            RejectReason::DuplicateInput(_) => write!(f, "Output spent twice by the transaction"),
+            RejectReason::MissingInputs(_)  => write!(f, "Missing parent transactions"),
+            RejectReason::OrphanTooLarge    => write!(f, "Orphan transaction too large"),
+            RejectReason::InvalidInput(_)   => write!(f, "Input spends an unknown or spent output"),
+            RejectReason::Conflict(_)       => write!(f, "Input already spent by a mempool transaction"),
+            RejectReason::TooLongChain      => write!(f, "Too long chain of unconfirmed transactions"),
+            RejectReason::TooManyReplacements => write!(f, "Replacement would evict too many transactions"),
+            RejectReason::SpendsReplaced    => write!(f, "Replacement spends a transaction it replaces"),
+            RejectReason::InsufficientReplacementFee => write!(f, "Replacement does not pay enough fees"),
+            RejectReason::ScriptFailed      => write!(f, "Script verification failed"),
+            RejectReason::NegativeFee       => write!(f, "Outputs are worth more than inputs"),
+            RejectReason::InsufficientFee(r) => write!(f, "Fee rate below the minimum of {} per kB", r),
+            RejectReason::MempoolFull       => write!(f, "Mempool full"),
+            RejectReason::Storage(e)        => write!(f, "Storage error: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+impl From<Error> for RejectReason {
+    fn from(e: Error) -> RejectReason {
+        RejectReason::Storage(e)
+    }
+}
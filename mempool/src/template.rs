@@ -0,0 +1,202 @@
+use std::cmp::Ordering;
+use std::collections::{ BinaryHeap, HashMap, HashSet };
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use blockchain::block::{ Block, BLOCK_REWARD };
+use blockchain::transaction::Transaction;
+use model::VarStr;
+use utils::Error;
+use utils::Size;
+use utils::merkle_tree::compute_merkle_root;
+use super::package::PackageStats;
+use super::Mempool;
+
+// header of a block carrying no flags, plus the biggest transaction count prefix
+const HEADER_SIZE: u64 = 4 + 1 + 32 + 32 + 8 + 4 + 32 + 8 + 9;
+
+// candidate block built on top of the current tip, handed to miners
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub version         : u32,
+    pub previous_hash   : Vec<u8>,
+    pub height          : u32,
+    pub timestamp       : u64,
+    pub difficulty      : Vec<u8>,
+    pub transactions    : Vec<Transaction>,
+    pub merkle_root     : Vec<u8>,
+    pub fees            : u64,
+    pub coinbase_value  : u64,
+}
+
+impl BlockTemplate {
+    pub fn block(&self, nonce: u64) -> Block {
+        Block {
+            version: self.version,
+            flags: Vec::new(),
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            timestamp: self.timestamp,
+            height: self.height,
+            difficulty: self.difficulty.clone(),
+            nonce,
+            transactions: self.transactions.clone(),
+            hash: Vec::new(),
+        }
+    }
+}
+
+// ancestor package of a transaction, without the transactions already selected
+struct Package {
+    ancestors   : HashSet<Vec<u8>>,
+    stats       : PackageStats,
+}
+
+// orders packages by fee rate, the heap holds outdated scores which are skipped
+#[derive(PartialEq, Eq)]
+struct Score {
+    stats   : PackageStats,
+    hash    : Vec<u8>,
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (u128::from(self.stats.fees) * u128::from(other.stats.size))
+            .cmp(&(u128::from(other.stats.fees) * u128::from(self.stats.size)))
+            .then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Mempool {
+    // picks packages by decreasing ancestor fee rate, so that a child paying a high fee
+    // pulls its unconfirmed parents in the block with it; the packages are computed once
+    // and only those of the descendants of selected transactions are updated
+    pub fn block_template(&self, previous_hash: Vec<u8>, previous: &Block, coinbase_script: VarStr, max_size: u64) -> Result<BlockTemplate, Error> {
+        let height = previous.height + 1;
+        let coinbase_size = Transaction::coinbase(height, 0, coinbase_script.clone()).size();
+        let mut block_size = HEADER_SIZE + coinbase_size;
+        let mut fees = 0;
+        let mut selected = Vec::new();
+
+        let mut packages = HashMap::new();
+        // parents have strictly less ancestors than their children
+        let mut depths = HashMap::new();
+        let mut scores = BinaryHeap::new();
+        for hash in self.txs.keys() {
+            let ancestors = self.ancestors(hash);
+            let stats = self.package_stats(&ancestors);
+            depths.insert(hash.clone(), ancestors.len());
+            scores.push(Score { stats, hash: hash.clone() });
+            packages.insert(hash.clone(), Package { ancestors, stats });
+        }
+
+        while let Some(Score { stats, hash }) = scores.pop() {
+            match packages.get(&hash) {
+                Some(package) if package.stats == stats => (),
+                _ => continue,
+            }
+            let package = packages.remove(&hash).unwrap();
+            if block_size + stats.size > max_size {
+                continue;
+            }
+
+            let mut hashes: Vec<Vec<u8>> = package.ancestors.into_iter().collect();
+            hashes.sort_by_key(|h| depths[h]);
+            block_size += stats.size;
+            fees += stats.fees;
+
+            let mut updated = HashSet::new();
+            for hash in &hashes {
+                packages.remove(hash);
+                let entry = &self.txs[hash];
+                for descendant in self.descendants(hash) {
+                    if let Some(package) = packages.get_mut(&descendant) {
+                        if package.ancestors.remove(hash) {
+                            package.stats.count -= 1;
+                            package.stats.size -= entry.size;
+                            package.stats.fees -= entry.fee;
+                            updated.insert(descendant);
+                        }
+                    }
+                }
+                selected.push(entry.tx.clone());
+            }
+            for hash in updated {
+                if let Some(package) = packages.get(&hash) {
+                    scores.push(Score { stats: package.stats, hash });
+                }
+            }
+        }
+
+        let coinbase_value = BLOCK_REWARD + fees;
+        let mut transactions = vec![Transaction::coinbase(height, coinbase_value, coinbase_script)];
+        transactions.append(&mut selected);
+
+        let mut hashes = Vec::new();
+        for tx in &transactions {
+            hashes.push(tx.hash()?);
+        }
+        let merkle_root = compute_merkle_root(hashes);
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        Ok(BlockTemplate {
+            version: previous.version,
+            previous_hash,
+            height,
+            timestamp: std::cmp::max(timestamp, previous.timestamp + 1),
+            difficulty: previous.difficulty.clone(),
+            transactions,
+            merkle_root,
+            fees,
+            coinbase_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block::MAX_BLOCK_SIZE;
+    use crate::testing::{ insert, tx };
+
+    fn previous() -> Block {
+        Block {
+            version: 0,
+            flags: Vec::new(),
+            previous_hash: vec![0; 32],
+            merkle_root: vec![0; 32],
+            timestamp: 0,
+            height: 0,
+            difficulty: vec![0; 32],
+            nonce: 0,
+            transactions: Vec::new(),
+            hash: Vec::new(),
+        }
+    }
+
+    fn selected(mempool: &Mempool, max_size: u64) -> Vec<Vec<u8>> {
+        let template = mempool.block_template(vec![0; 32], &previous(), VarStr::from_string(String::new()), max_size).unwrap();
+        template.transactions[1..].iter().map(|tx| tx.hash().unwrap()).collect()
+    }
+
+    #[test]
+    fn child_pulls_its_parent_in() {
+        let mut mempool = Mempool::new();
+        let parent = insert(&mut mempool, &tx(&[(vec![1; 32], 0)], &[10]), 0);
+        let child = insert(&mut mempool, &tx(&[(parent.clone(), 0)], &[9]), 10_000);
+        let other = insert(&mut mempool, &tx(&[(vec![2; 32], 0)], &[10]), 2_000);
+        let grandchild = insert(&mut mempool, &tx(&[(child.clone(), 0)], &[8]), 1);
+        assert_eq!(selected(&mempool, MAX_BLOCK_SIZE), vec![parent, child, other.clone(), grandchild]);
+
+        // the parent and child package doesn't fit, the other transaction does
+        let size = mempool.txs[&other].size;
+        let coinbase_size = Transaction::coinbase(1, 0, VarStr::from_string(String::new())).size();
+        assert_eq!(selected(&mempool, HEADER_SIZE + coinbase_size + size), vec![other]);
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use blockchain::transaction::{ Outpoint, Transaction, TxIn, TxOut };
+use model::{ VarStr, VarUint };
+use utils::Size;
+use super::{ limits, Mempool, MempoolEntry };
+
+pub fn tx(inputs: &[(Vec<u8>, u32)], values: &[u64]) -> Transaction {
+    Transaction {
+        version: 0,
+        flags_count: VarUint::from_u64(0),
+        flags: Vec::new(),
+        inputs_count: VarUint::from_u64(inputs.len() as u64),
+        inputs: inputs.iter().map(|(hash, index)| TxIn {
+            previous_output: Outpoint { hash: hash.clone(), index: *index },
+            script: VarStr::from_string(String::new()),
+            shash: Vec::new(),
+        }).collect(),
+        outputs_count: VarUint::from_u64(values.len() as u64),
+        outputs: values.iter().map(|value| TxOut {
+            value: *value,
+            script: VarStr::from_string(String::new()),
+        }).collect(),
+    }
+}
+
+// adds tx without looking its inputs up, linking it to its mempool parents
+pub fn insert(mempool: &mut Mempool, tx: &Transaction, fee: u64) -> Vec<u8> {
+    let hash = tx.hash().unwrap();
+    let parents: HashSet<Vec<u8>> = mempool.parents(tx).into_iter().collect();
+    for parent in &parents {
+        mempool.txs.get_mut(parent).unwrap().children.insert(hash.clone());
+    }
+    for input in &tx.inputs {
+        mempool.outpoints.insert(input.previous_output.clone(), hash.clone());
+    }
+    let entry = MempoolEntry {
+        tx: tx.clone(),
+        size: tx.size(),
+        fee,
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        parents,
+        children: HashSet::new(),
+    };
+    mempool.usage += limits::entry_usage(&entry);
+    mempool.txs.insert(hash.clone(), entry);
+    hash
+}
@@ -6,11 +6,13 @@ use cookie_factory::bytes::be_u32;
 use cookie_factory::bytes::be_u64;
 use cookie_factory::sequence::tuple;
 use cookie_factory::SerializeFn;
+use std::convert::TryFrom;
 use std::io::Write;
 use bincode::deserialize;
 use bincode::serialize;
+use utils::Error;
 use utils::Size;
-use std::net::IpAddr;
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr };
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
@@ -56,6 +58,32 @@ impl Address {
         })
     }
 
+    pub fn from_socket_addr(address: &SocketAddr, timestamp: u64) -> Address {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        Address {
+            timestamp,
+            ip: ip.octets().to_vec(),
+            port: address.port(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&self.ip);
+        let ip = Ipv6Addr::from(bytes);
+        match ip.to_ipv4() {
+            Some(v4) if ip.segments()[5] == 0xffff => SocketAddr::new(IpAddr::V4(v4), self.port),
+            _ => SocketAddr::new(IpAddr::V6(ip), self.port),
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
     fn serialize<'c, W: Write + 'c>(&self) -> impl SerializeFn<W> + 'c {
         tuple((
             be_u64(self.timestamp),
@@ -88,51 +116,61 @@ impl Size for Address {
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct VarUint {
+    // encoded length, the prefix byte included
     size: u8,
     pub value: u64,
 }
 impl VarUint {
     pub fn new(payload: &[u8]) -> Self {
-        let mut size = payload[0];
-        match size {
-            0xFD => {
-                size = 2;
-            }
-            0xFE => {
-                size = 4;
-            }
-            0xFF => {
-                size = 8;
-            }
-            _ => {
+        let width = match payload[0] {
+            0xFD => 2,
+            0xFE => 4,
+            0xFF => 8,
+            value => {
                 return Self {
                     size: 1,
-                    value: u64::from(size),
+                    value: u64::from(value),
                 };
             }
-        }
+        };
 
-        let mut value = payload[1..=size as usize].to_vec();
+        let mut value = payload[1..=width as usize].to_vec();
         value.reverse();
-        value.append(&mut vec![0; (8-size) as usize]);
+        value.append(&mut vec![0; (8-width) as usize]);
         let value: u64 = deserialize(&value).unwrap();
 
         Self {
-            size,
+            size: width + 1,
             value,
         }
     }
 
+    // like new, but fails instead of panicking when payload is too short
+    pub fn read(payload: &[u8]) -> Result<Self, Error> {
+        let size = match payload.first() {
+            Some(0xFD) => 3,
+            Some(0xFE) => 5,
+            Some(0xFF) => 9,
+            Some(_) => 1,
+            None => 0,
+        };
+        if size == 0 || payload.len() < size {
+            return Err(Error::ParseError("var_uint out of the buffer".to_string()))
+        }
+        Ok(Self::new(payload))
+    }
+
     pub fn from_u64(value: u64) -> Self {
+        // must agree with serialize
         let size;
-        if value < 252 {
+        if value <= 252 {
             size = 1;
         } else if value <= 0xFFFF {
-            size = 2;
+            size = 3;
         } else if value <= 0xFFFFFFFF {
-            size = 4;
+            size = 5;
         } else {
-            size = 8;
+            size = 9;
         }
         Self {
             size,
@@ -189,7 +227,7 @@ impl VarStr {
     pub fn new(payload: &[u8]) -> Self {
         let length: VarUint = VarUint::new(payload);
         let size = length.size() as usize;
-        let value = payload[size..=length.value as usize].to_vec();
+        let value = payload[size..size + length.value as usize].to_vec();
         let value = String::from_utf8(value).unwrap();
         Self {
             size: length,
@@ -197,6 +235,22 @@ impl VarStr {
         }
     }
 
+    // like new, but fails instead of panicking when payload is too short or the
+    // string isn't utf-8, it is never rewritten as that would change hashes
+    pub fn read(payload: &[u8]) -> Result<Self, Error> {
+        let length = VarUint::read(payload)?;
+        let start = length.size() as usize;
+        let end = match usize::try_from(length.value).ok().and_then(|l| start.checked_add(l)) {
+            Some(end) if end <= payload.len() => end,
+            _ => return Err(Error::ParseError("var_str out of the buffer".to_string())),
+        };
+        let value = String::from_utf8(payload[start..end].to_vec())?;
+        Ok(Self {
+            size: length,
+            value,
+        })
+    }
+
     pub fn from_string(value: String) -> Self {
         Self {
             size: VarUint::from_u64(value.len() as u64),
@@ -229,15 +283,18 @@ impl InvVect {
         }
     }
 
-    pub fn read(buffer: &[u8]) -> Self {
+    pub fn read(buffer: &[u8]) -> Result<Self, Error> {
+        if buffer.len() < 36 {
+            return Err(Error::ParseError("inventory item out of the buffer".to_string()))
+        }
         let mut hash_type = buffer[0..4].to_vec();
         hash_type.reverse();
-        let hash_type : u32 = deserialize(&hash_type).unwrap();
+        let hash_type : u32 = deserialize(&hash_type)?;
 
-        InvVect {
+        Ok(InvVect {
             hash_type,
-            hash:   buffer[4..=34].to_vec()
-        }
+            hash:   buffer[4..36].to_vec()
+        })
     }
 
     pub fn send(&self) -> Vec<u8> {
@@ -254,3 +311,42 @@ impl Size for InvVect {
         36
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_uint_size_matches_encoding() {
+        for value in &[0, 252, 253, 0xFFFF, 0x10000, 0xFFFF_FFFF, 0x1_0000_0000] {
+            let var_uint = VarUint::from_u64(*value);
+            let bytes = var_uint.send();
+            assert_eq!(var_uint.size(), bytes.len() as u64);
+            let read = VarUint::new(&bytes);
+            assert_eq!(read.value, *value);
+            assert_eq!(read.size(), bytes.len() as u64);
+        }
+    }
+
+    #[test]
+    fn reads_fail_out_of_the_buffer() {
+        assert!(VarUint::read(&[]).is_err());
+        assert!(VarUint::read(&[0xFE, 1, 2]).is_err());
+        assert!(VarStr::read(&[3, b'a', b'b']).is_err());
+        assert!(VarStr::read(&[2, 0xFF, 0xFE]).is_err());
+        assert!(InvVect::read(&[0; 35]).is_err());
+        let inv = InvVect::read(&[0xAB; 36]).unwrap();
+        assert_eq!(inv.hash.len(), 32);
+    }
+
+    #[test]
+    fn var_str_round_trip() {
+        for length in &[0, 5, 300] {
+            let var_str = VarStr::from_string("a".repeat(*length));
+            let bytes = var_str.send();
+            assert_eq!(var_str.size(), bytes.len() as u64);
+            assert_eq!(VarStr::read(&bytes).unwrap(), var_str);
+            assert_eq!(VarStr::new(&bytes), var_str);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 use std::fs::File;
 use std::io::{BufWriter, BufReader};
-use dirs::data_dir;
+use utils::data_dir;
 
 use utils::Error;
 use blockchain::Blockchain;
@@ -10,11 +10,13 @@ use blockchain::Blockchain;
 struct Config {
     blockchain_exists: bool,
     matrix_access_token: String,
+    // blocks are stored under their header hash
+    #[serde(default)]
+    header_hashes: bool,
 }
 
 pub fn read_config() -> Result<(), Error> {
-    let mut path = data_dir().unwrap();
-    path.push("ensicoin-rust/");
+    let mut path = data_dir();
     path.push("config.json");
     let f = if let Ok(f) =  File::open(path.clone()) {
         f
@@ -28,6 +30,7 @@ pub fn read_config() -> Result<(), Error> {
         let c = Config {
             blockchain_exists: false,
             matrix_access_token: "".to_owned(),
+            header_hashes: true,
         };
         serde_json::to_writer_pretty(&tmp, &c).unwrap();
         tmp
@@ -35,9 +38,14 @@ pub fn read_config() -> Result<(), Error> {
     let reader = BufReader::new(f);
 
     let mut config : Config = serde_json::from_reader(reader).unwrap();
-    if !config.blockchain_exists {
-        Blockchain::add_genesis_block()?;
+    if !config.blockchain_exists || !config.header_hashes {
+        if config.blockchain_exists {
+            Blockchain::migrate_header_hashes()?;
+        } else {
+            Blockchain::add_genesis_block()?;
+        }
         config.blockchain_exists = true;
+        config.header_hashes = true;
 
         let f = std::fs::OpenOptions::new()
                     .truncate(true)
@@ -14,8 +14,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_target(true)
         .inherit_fields(true)
         .finish()).unwrap();
-    let server = Server::new();
+    let mut server = Server::new(args.max_mempool * 1_000_000, args.max_outbound, args.max_inbound, args.ban_time, args.external_ip, args.max_message_size);
     server.interactive().await;
+    server.handle_signals().await;
+    if let Some(port) = args.stratum {
+        server.stratum(port, args.payout.clone()).await;
+    }
+    if let Some(port) = args.rpc {
+        server.json_rpc(port, args.payout).await;
+    }
     server.listen(args.port).await?;
     Ok(())
 }
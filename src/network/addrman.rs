@@ -0,0 +1,354 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{ HashMap, HashSet };
+use std::hash::{ Hash, Hasher };
+use std::net::{ IpAddr, SocketAddr };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use utils::open_db;
+use rand::Rng;
+use rand::seq::IteratorRandom;
+use serde::{ Serialize, Deserialize };
+use sled::Db;
+use utils::error::Error;
+
+const NEW_BUCKETS               : usize = 256;
+const TRIED_BUCKETS             : usize = 64;
+const BUCKET_SIZE               : usize = 64;
+// addresses given by one source group only spread over this many new buckets,
+// so a single source can't flood the table
+const NEW_BUCKETS_PER_SOURCE    : u64   = 32;
+const TRIED_BUCKETS_PER_GROUP   : u64   = 8;
+
+// addresses not seen for a month are forgotten
+const HORIZON                   : u64   = 30 * 24 * 60 * 60;
+// new addresses failing this many times in a row are forgotten
+const RETRIES                   : u32   = 3;
+// addresses failing this many times and not working for a week are forgotten
+const MAX_FAILURES              : u32   = 10;
+const MIN_FAIL                  : u64   = 7 * 24 * 60 * 60;
+const RECENT_ATTEMPT            : u64   = 10 * 60;
+// share of the table sent in reply to getaddr
+const GETADDR_PERCENT           : usize = 23;
+
+const KEY                       : &[u8] = b"\0key";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddrInfo {
+    pub addr            : SocketAddr,
+    // peer which told us about this address
+    pub source          : IpAddr,
+    pub services        : Vec<String>,
+    pub last_seen       : u64,
+    pub last_success    : u64,
+    pub last_attempt    : u64,
+    // failed attempts since the last success
+    pub attempts        : u32,
+    pub tried           : bool,
+}
+
+impl AddrInfo {
+    fn is_terrible(&self, now: u64) -> bool {
+        if self.last_attempt + 60 >= now {
+            return false
+        }
+        self.last_seen > now + 10 * 60
+            || self.last_seen + HORIZON < now
+            || (self.last_success == 0 && self.attempts >= RETRIES)
+            || (self.last_success + MIN_FAIL < now && self.attempts >= MAX_FAILURES)
+    }
+
+    // relative chance of being picked for an outbound connection
+    fn chance(&self, now: u64) -> f64 {
+        let mut chance = 0.66f64.powi(std::cmp::min(self.attempts, 8) as i32);
+        if self.last_attempt + RECENT_ATTEMPT > now {
+            chance *= 0.01;
+        }
+        chance
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// network group of an address, /16 for IPv4 and /32 for IPv6
+pub fn group(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[0..2].to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) if ip.octets() != [0, 0, 0, 1] => ip.octets()[0..2].to_vec(),
+            _ => ip.octets()[0..4].to_vec(),
+        },
+    }
+}
+
+// addresses of potential peers, split into a new table for addresses we only heard of
+// and a tried table for the ones we successfully connected to
+pub struct AddrMan {
+    db      : Db,
+    key     : u64,
+    infos   : HashMap<SocketAddr, AddrInfo>,
+    new     : Vec<HashSet<SocketAddr>>,
+    tried   : Vec<HashSet<SocketAddr>>,
+}
+
+impl AddrMan {
+    fn open() -> Result<Db, Error> {
+        open_db("known_peers")
+    }
+
+    pub fn load() -> Result<AddrMan, Error> {
+        let db = AddrMan::open()?;
+        let key = match db.get(KEY)? {
+            Some(k) if k.len() == 8 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&k);
+                u64::from_be_bytes(bytes)
+            },
+            _ => {
+                let key = rand::thread_rng().gen();
+                db.insert(KEY, &u64::to_be_bytes(key))?;
+                key
+            },
+        };
+
+        let mut addrman = AddrMan {
+            db,
+            key,
+            infos   : HashMap::new(),
+            new     : vec![HashSet::new(); NEW_BUCKETS],
+            tried   : vec![HashSet::new(); TRIED_BUCKETS],
+        };
+        let entries : Vec<(Vec<u8>, Vec<u8>)> = addrman.db.iter()
+            .filter_map(|x| x.ok())
+            .filter(|(k, _)| &k[..] != KEY)
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+        for (k, v) in entries {
+            match bincode::deserialize::<AddrInfo>(&v) {
+                Ok(info) => addrman.insert(info),
+                // bare addresses saved before metadata was recorded
+                Err(_) => {
+                    addrman.db.remove(&k)?;
+                    if let Ok(addr) = String::from_utf8(k).unwrap_or_default().parse::<SocketAddr>() {
+                        addrman.add(addr, addr.ip(), now());
+                    }
+                },
+            }
+        }
+        Ok(addrman)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn hash<T: Hash>(&self, data: T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.key.hash(&mut hasher);
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn new_bucket(&self, addr: &SocketAddr, source: &IpAddr) -> usize {
+        let source_group = group(source);
+        let slot = self.hash((group(&addr.ip()), &source_group)) % NEW_BUCKETS_PER_SOURCE;
+        (self.hash((&source_group, slot)) % NEW_BUCKETS as u64) as usize
+    }
+
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        let slot = self.hash(addr) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash((group(&addr.ip()), slot)) % TRIED_BUCKETS as u64) as usize
+    }
+
+    fn save(&self, info: &AddrInfo) {
+        match bincode::serialize(info) {
+            Ok(v) => {
+                if let Err(e) = self.db.insert(info.addr.to_string(), v) {
+                    tracing::warn!("Known Peers database probably dead: {:?}", e);
+                }
+            },
+            Err(e) => tracing::warn!("could not serialize address: {:?}", e),
+        }
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.infos.remove(addr) {
+            if info.tried {
+                let bucket = self.tried_bucket(addr);
+                self.tried[bucket].remove(addr);
+            } else {
+                let bucket = self.new_bucket(addr, &info.source);
+                self.new[bucket].remove(addr);
+            }
+            if let Err(e) = self.db.remove(addr.to_string()) {
+                tracing::warn!("Known Peers database probably dead: {:?}", e);
+            }
+        }
+    }
+
+    // puts an entry in its bucket, making room when it is full
+    fn insert(&mut self, info: AddrInfo) {
+        let now = now();
+        let (bucket, tried) = if info.tried {
+            (self.tried_bucket(&info.addr), true)
+        } else {
+            (self.new_bucket(&info.addr, &info.source), false)
+        };
+        let table = if tried { &self.tried[bucket] } else { &self.new[bucket] };
+        if table.len() >= BUCKET_SIZE {
+            // a terrible entry goes first, then the one seen the longest ago
+            let victim = table.iter()
+                .map(|a| &self.infos[a])
+                .min_by_key(|i| (!i.is_terrible(now), if tried { i.last_success } else { i.last_seen }))
+                .map(|i| i.addr);
+            if let Some(victim) = victim {
+                let evicted = self.infos[&victim].clone();
+                self.remove(&victim);
+                // tried addresses get a second chance in the new table
+                if evicted.tried {
+                    let bucket = self.new_bucket(&evicted.addr, &evicted.source);
+                    if self.new[bucket].len() < BUCKET_SIZE {
+                        self.insert(AddrInfo { tried: false, ..evicted });
+                    }
+                }
+            }
+        }
+
+        self.save(&info);
+        if tried {
+            self.tried[bucket].insert(info.addr);
+        } else {
+            self.new[bucket].insert(info.addr);
+        }
+        self.infos.insert(info.addr, info);
+    }
+
+    // an address was advertised by source, returns true if it was new
+    pub fn add(&mut self, addr: SocketAddr, source: IpAddr, timestamp: u64) -> bool {
+        let now = now();
+        // an advertised time in the future is not trusted
+        let timestamp = std::cmp::min(timestamp, now);
+        if let Some(info) = self.infos.get_mut(&addr) {
+            if timestamp > info.last_seen {
+                info.last_seen = timestamp;
+                let info = info.clone();
+                self.save(&info);
+            }
+            return false
+        }
+        self.insert(AddrInfo {
+            addr,
+            source,
+            services        : Vec::new(),
+            last_seen       : timestamp,
+            last_success    : 0,
+            last_attempt    : 0,
+            attempts        : 0,
+            tried           : false,
+        });
+        true
+    }
+
+    // we are about to connect to this address
+    pub fn attempt(&mut self, addr: &SocketAddr) {
+        let now = now();
+        if let Some(info) = self.infos.get_mut(addr) {
+            info.last_attempt = now;
+            info.attempts += 1;
+            let info = info.clone();
+            self.save(&info);
+        }
+    }
+
+    // the handshake with this address succeeded, it moves to the tried table
+    pub fn good(&mut self, addr: SocketAddr, services: Vec<String>) {
+        let now = now();
+        let mut info = match self.infos.get(&addr) {
+            Some(info) => info.clone(),
+            None => AddrInfo {
+                addr,
+                source          : addr.ip(),
+                services        : Vec::new(),
+                last_seen       : now,
+                last_success    : 0,
+                last_attempt    : now,
+                attempts        : 0,
+                tried           : false,
+            },
+        };
+        self.remove(&addr);
+        info.services = services;
+        info.last_seen = now;
+        info.last_success = now;
+        info.attempts = 0;
+        info.tried = true;
+        self.insert(info);
+    }
+
+    // picks an address to connect to, reliable addresses are more likely to be picked
+    pub fn select(&self) -> Option<&AddrInfo> {
+        let now = now();
+        let tried = self.tried.iter().any(|b| !b.is_empty());
+        let new = self.new.iter().any(|b| !b.is_empty());
+        if !tried && !new {
+            return None
+        }
+
+        let mut rng = rand::thread_rng();
+        let use_tried = tried && (!new || rng.gen_bool(0.5));
+        let table = if use_tried { &self.tried } else { &self.new };
+        let mut factor = 1.0;
+        loop {
+            let bucket = table.iter().filter(|b| !b.is_empty()).choose(&mut rng)?;
+            let addr = bucket.iter().choose(&mut rng)?;
+            let info = &self.infos[addr];
+            if rng.gen_range(0.0, 1.0) < info.chance(now) * factor {
+                return Some(info)
+            }
+            factor *= 1.2;
+        }
+    }
+
+    // random sample of addresses worth advertising, at most max
+    // a small table still advertises at least one address
+    pub fn sample(&self, max: usize) -> Vec<AddrInfo> {
+        let now = now();
+        let count = std::cmp::min(max, std::cmp::max(1, self.infos.len() * GETADDR_PERCENT / 100));
+        self.infos.values()
+            .filter(|i| !i.is_terrible(now))
+            .cloned()
+            .choose_multiple(&mut rand::thread_rng(), count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrman() -> AddrMan {
+        AddrMan {
+            db      : sled::Config::new().temporary(true).open().unwrap(),
+            key     : 0,
+            infos   : HashMap::new(),
+            new     : vec![HashSet::new(); NEW_BUCKETS],
+            tried   : vec![HashSet::new(); TRIED_BUCKETS],
+        }
+    }
+
+    #[test]
+    fn small_table_still_sampled() {
+        let mut addrman = addrman();
+        assert!(addrman.sample(1000).is_empty());
+        let addr : SocketAddr = "10.0.0.1:4224".parse().unwrap();
+        addrman.add(addr, addr.ip(), now());
+        assert_eq!(addrman.sample(1000).len(), 1);
+        assert!(addrman.sample(0).is_empty());
+
+        for i in 2..10 {
+            let addr : SocketAddr = format!("10.0.{}.1:4224", i).parse().unwrap();
+            addrman.add(addr, addr.ip(), now());
+        }
+        assert_eq!(addrman.sample(1000).len(), 2);
+    }
+}
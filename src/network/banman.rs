@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use utils::open_db;
+use sled::Db;
+use utils::error::Error;
+
+// misbehavior score at which a peer gets banned
+pub const BAN_THRESHOLD : u32 = 100;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// banned ips with the time their ban ends
+pub struct BanMan {
+    db          : Db,
+    banned      : HashMap<IpAddr, u64>,
+    ban_time    : u64,
+}
+
+impl BanMan {
+    fn open() -> Result<Db, Error> {
+        open_db("banned")
+    }
+
+    pub fn load(ban_time: u64) -> Result<BanMan, Error> {
+        let db = BanMan::open()?;
+        let mut banned = HashMap::new();
+        for entry in db.iter() {
+            let (ip, until) = entry?;
+            let ip = match String::from_utf8(ip.to_vec()).ok().and_then(|ip| ip.parse().ok()) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            if until.len() == 8 {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&until);
+                banned.insert(ip, u64::from_be_bytes(bytes));
+            }
+        }
+        let mut banman = BanMan {
+            db,
+            banned,
+            ban_time,
+        };
+        banman.sweep()?;
+        Ok(banman)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(until) => *until > now(),
+            None => false,
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) -> Result<(), Error> {
+        let until = now() + self.ban_time;
+        self.banned.insert(ip, until);
+        self.db.insert(ip.to_string(), &until.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // current bans with the time they end
+    pub fn list(&mut self) -> Result<Vec<(IpAddr, u64)>, Error> {
+        self.sweep()?;
+        let mut bans : Vec<(IpAddr, u64)> = self.banned.iter().map(|(ip, until)| (*ip, *until)).collect();
+        bans.sort_by_key(|(_, until)| *until);
+        Ok(bans)
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.banned.clear();
+        self.db.clear()?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // forgets the bans which ended
+    fn sweep(&mut self) -> Result<(), Error> {
+        let now = now();
+        let expired : Vec<IpAddr> = self.banned.iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in expired {
+            self.banned.remove(&ip);
+            self.db.remove(ip.to_string())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use bytes::{ BufMut, BytesMut };
+use tokio_util::codec::{ Decoder, Encoder };
+use super::message::Message;
+use utils::Error;
+use utils::ToBytes;
+
+const MAGIC                 : u32   = 42_2021;
+// magic, message type and payload length, followed by the checksum when it is used
+const HEADER_SIZE           : usize = 24;
+const CHECKSUM_SIZE         : usize = 4;
+
+// first 4 bytes of the double sha256 of the payload
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    utils::hash(utils::hash(payload.to_vec()))[0..4].to_vec()
+}
+
+fn is_handshake(name: &str) -> bool {
+    let name = name.trim_end_matches('\u{0}');
+    name == "whoami" || name == "whoamiack"
+}
+
+// frames messages on a peer connection, the same checksum flag is shared by the
+// reading and writing halves
+pub struct MessageCodec {
+    checksum            : Arc<AtomicBool>,
+    // larger messages are refused before their payload is buffered
+    max_message_size    : u64,
+}
+
+impl MessageCodec {
+    pub fn new(checksum: Arc<AtomicBool>, max_message_size: u64) -> Self {
+        Self {
+            checksum,
+            max_message_size,
+        }
+    }
+
+    // whoami and whoamiack are never checksummed, every later message is
+    // once both sides agreed on it
+    fn has_checksum(&self, name: &str) -> bool {
+        self.checksum.load(Ordering::Acquire) && !is_handshake(name)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        if src.len() < HEADER_SIZE {
+            return Ok(None)
+        }
+        let mut magic = [0; 4];
+        magic.copy_from_slice(&src[0..4]);
+        if u32::from_be_bytes(magic) != MAGIC {
+            return Err(Error::WrongMagic)
+        }
+        let name = String::from_utf8(src[4..16].to_vec())?;
+        let mut length = [0; 8];
+        length.copy_from_slice(&src[16..24]);
+        let length = u64::from_be_bytes(length);
+        if length > self.max_message_size {
+            return Err(Error::MessageTooLarge)
+        }
+
+        let has_checksum = self.has_checksum(&name);
+        let header_size = if has_checksum { HEADER_SIZE + CHECKSUM_SIZE } else { HEADER_SIZE };
+        let frame_size = header_size + length as usize;
+        // the buffer grows as the payload arrives, nothing is allocated for the announced length
+        if src.len() < frame_size {
+            return Ok(None)
+        }
+
+        let frame = src.split_to(frame_size);
+        let payload = &frame[header_size..];
+        if has_checksum && frame[HEADER_SIZE..header_size] != checksum(payload)[..] {
+            return Err(Error::WrongChecksum)
+        }
+        Message::read(&name, payload).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        let name = message.name();
+        let payload = message.send();
+        dst.reserve(HEADER_SIZE + CHECKSUM_SIZE + payload.len());
+        dst.put_u32(MAGIC);
+        dst.put_slice(name.as_bytes());
+        if name.len() < 12 {
+            dst.put_slice(&vec![0; 12 - name.len()]);
+        }
+        // the length of what is actually written, whatever size() says
+        dst.put_u64(payload.len() as u64);
+        if self.has_checksum(name) {
+            dst.put_slice(&checksum(&payload));
+        }
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::transaction::Transaction;
+    use model::{ Address, VarStr };
+    use crate::network::message::Addr;
+
+    fn codec(checksum: bool) -> MessageCodec {
+        MessageCodec::new(Arc::new(AtomicBool::new(checksum)), 1024)
+    }
+
+    fn encode(codec: &mut MessageCodec, message: Message) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        codec.encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn header_split_across_reads() {
+        let mut codec = codec(false);
+        let frame = encode(&mut codec, Message::Ping(42));
+        let mut src = BytesMut::from(&frame[..10]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&frame[10..]);
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::Ping(42))));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn payload_split_across_reads() {
+        let mut codec = codec(true);
+        let frame = encode(&mut codec, Message::Pong(7));
+        let mut src = BytesMut::from(&frame[..frame.len() - 3]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), frame.len() - 3);
+        src.extend_from_slice(&frame[frame.len() - 3..]);
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::Pong(7))));
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let mut codec = codec(true);
+        let mut src = encode(&mut codec, Message::Ping(1));
+        src.extend_from_slice(&encode(&mut codec, Message::GetAddr));
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::Ping(1))));
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::GetAddr)));
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn variable_length_messages_round_trip() {
+        let mut codec = codec(true);
+        let from = "127.0.0.1:4224".parse().unwrap();
+        let addr = Addr::from_vec(vec![Address::from_socket_addr(&from, 1), Address::from_socket_addr(&from, 2)]);
+        let transaction = Transaction::coinbase(7, 50, VarStr::from_string("payout".to_string()));
+        let mut src = encode(&mut codec, Message::Addr(addr));
+        src.extend_from_slice(&encode(&mut codec, Message::Transaction(transaction.clone())));
+        src.extend_from_slice(&encode(&mut codec, Message::Ping(9)));
+        match codec.decode(&mut src).unwrap() {
+            Some(Message::Addr(addr)) => assert_eq!(addr.addresses.len(), 2),
+            _ => panic!("addr not decoded"),
+        }
+        match codec.decode(&mut src).unwrap() {
+            Some(Message::Transaction(read)) => assert_eq!(read.hash().unwrap(), transaction.hash().unwrap()),
+            _ => panic!("transaction not decoded"),
+        }
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::Ping(9))));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn oversized_message_rejected_from_header() {
+        let mut codec = codec(false);
+        let mut src = BytesMut::new();
+        src.put_u32(MAGIC);
+        src.put_slice(b"block\0\0\0\0\0\0\0");
+        src.put_u64(1025);
+        assert!(matches!(codec.decode(&mut src), Err(Error::MessageTooLarge)));
+    }
+
+    #[test]
+    fn wrong_checksum_rejected() {
+        let mut codec = codec(true);
+        let mut src = encode(&mut codec, Message::Ping(3));
+        src[HEADER_SIZE] ^= 0xFF;
+        assert!(matches!(codec.decode(&mut src), Err(Error::WrongChecksum)));
+
+        // handshake messages are never checksummed
+        let mut src = encode(&mut codec, Message::WhoAmIAck);
+        assert_eq!(src.len(), HEADER_SIZE);
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(Message::WhoAmIAck)));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::{ HashMap, HashSet };
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+use super::addrman::{ self, AddrMan };
+
+pub const CONNECT_INTERVAL      : Duration  = Duration::from_secs(5);
+pub const CONNECT_TIMEOUT       : Duration  = Duration::from_secs(5);
+// delay before retrying an address, doubled after each failure
+const BACKOFF_BASE              : Duration  = Duration::from_secs(30);
+const BACKOFF_MAX               : Duration  = Duration::from_secs(60 * 60);
+// addresses drawn from the address manager before giving up for this round
+const SELECT_TRIES              : usize     = 100;
+
+// keeps max_outbound outbound connections open, each one to a different network group
+pub struct ConnectionManager {
+    max_outbound    : usize,
+    outbound        : HashSet<SocketAddr>,
+    connecting      : HashSet<SocketAddr>,
+    // failures in a row and when the address may be tried again
+    backoff         : HashMap<SocketAddr, (u32, Instant)>,
+}
+
+impl ConnectionManager {
+    pub fn new(max_outbound: usize) -> Self {
+        Self {
+            max_outbound,
+            outbound        : HashSet::new(),
+            connecting      : HashSet::new(),
+            backoff         : HashMap::new(),
+        }
+    }
+
+    // addresses to connect to now, they are considered connecting until
+    // connected or failed is called
+    pub fn candidates<F>(&mut self, addrman: &AddrMan, is_connected: F) -> Vec<SocketAddr>
+        where F: Fn(&SocketAddr) -> bool {
+        let now = Instant::now();
+        // failures long past are forgotten
+        self.backoff.retain(|_, (_, retry)| *retry + BACKOFF_MAX > now);
+
+        let mut groups : HashSet<Vec<u8>> = self.outbound.iter()
+            .chain(self.connecting.iter())
+            .map(|a| addrman::group(&a.ip()))
+            .collect();
+        let mut candidates = Vec::new();
+        let mut tries = 0;
+        while self.outbound.len() + self.connecting.len() < self.max_outbound && tries < SELECT_TRIES {
+            tries += 1;
+            let addr = match addrman.select() {
+                Some(info) => info.addr,
+                None => break,
+            };
+            if is_connected(&addr) || self.connecting.contains(&addr) || self.outbound.contains(&addr) {
+                continue;
+            }
+            if let Some((_, retry)) = self.backoff.get(&addr) {
+                if *retry > now {
+                    continue;
+                }
+            }
+            if !groups.insert(addrman::group(&addr.ip())) {
+                continue;
+            }
+            self.connecting.insert(addr);
+            candidates.push(addr);
+        }
+        candidates
+    }
+
+    // the handshake with an address we connected to completed
+    pub fn connected(&mut self, addr: SocketAddr) {
+        if self.connecting.remove(&addr) {
+            self.outbound.insert(addr);
+        }
+        self.backoff.remove(&addr);
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr) {
+        self.connecting.remove(&addr);
+        let failures = self.backoff.get(&addr).map(|(f, _)| *f).unwrap_or(0) + 1;
+        let delay = std::cmp::min(BACKOFF_BASE * 2u32.pow(std::cmp::min(failures, 16) - 1), BACKOFF_MAX);
+        self.backoff.insert(addr, (failures, Instant::now() + delay));
+    }
+
+    pub fn disconnected(&mut self, addr: &SocketAddr) {
+        if self.connecting.contains(addr) {
+            self.failed(*addr);
+        }
+        self.outbound.remove(addr);
+    }
+}
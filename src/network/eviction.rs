@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+use rand::seq::SliceRandom;
+use super::addrman;
+
+// inbound sockets still in the handshake, they don't take an inbound slot
+pub const MAX_HANDSHAKING   : usize = 16;
+
+const PROTECT_BY_GROUP      : usize = 4;
+const PROTECT_BY_LATENCY    : usize = 8;
+const PROTECT_BY_TX         : usize = 4;
+const PROTECT_BY_BLOCK      : usize = 4;
+
+// what is known about an inbound peer to decide whether it can be evicted
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub addr        : SocketAddr,
+    pub connected   : Instant,
+    pub last_block  : Option<Instant>,
+    pub last_tx     : Option<Instant>,
+    pub min_ping    : Option<Duration>,
+    pub group       : Vec<u8>,
+}
+
+impl EvictionCandidate {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            connected   : Instant::now(),
+            last_block  : None,
+            last_tx     : None,
+            min_ping    : None,
+            group       : addrman::group(&addr.ip()),
+        }
+    }
+}
+
+// removes the n last candidates once sorted by key, they are protected from eviction
+fn protect<K: Ord, F: Fn(&EvictionCandidate) -> K>(candidates: &mut Vec<EvictionCandidate>, n: usize, key: F) {
+    candidates.sort_by_key(key);
+    let keep = candidates.len().saturating_sub(n);
+    candidates.truncate(keep);
+}
+
+// picks the inbound peer to disconnect to make room for a new one, none when every
+// peer is protected. Each protection is based on something an attacker can't easily fake.
+pub fn select_to_evict(mut candidates: Vec<EvictionCandidate>) -> Option<SocketAddr> {
+    // peers from a few random network groups
+    let mut groups : Vec<Vec<u8>> = candidates.iter().map(|c| c.group.clone()).collect();
+    groups.sort();
+    groups.dedup();
+    groups.shuffle(&mut rand::thread_rng());
+    protect(&mut candidates, PROTECT_BY_GROUP, |c| {
+        std::cmp::Reverse(groups.iter().position(|g| *g == c.group))
+    });
+    // the fastest peers, peers without a measured latency sort last
+    protect(&mut candidates, PROTECT_BY_LATENCY, |c| std::cmp::Reverse(c.min_ping.unwrap_or(Duration::from_secs(u64::MAX / 2))));
+    // peers which recently relayed new transactions or blocks to us
+    protect(&mut candidates, PROTECT_BY_TX, |c| c.last_tx);
+    protect(&mut candidates, PROTECT_BY_BLOCK, |c| c.last_block);
+    // the oldest half of the remaining peers
+    let half = candidates.len() / 2;
+    protect(&mut candidates, half, |c| std::cmp::Reverse(c.connected));
+
+    // the youngest peer of the most represented network group
+    let mut by_group : HashMap<Vec<u8>, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates {
+        by_group.entry(candidate.group.clone()).or_default().push(candidate);
+    }
+    let (_, group) = by_group.into_iter().max_by_key(|(_, peers)| peers.len())?;
+    group.into_iter().max_by_key(|c| c.connected).map(|c| c.addr)
+}
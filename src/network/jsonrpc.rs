@@ -0,0 +1,141 @@
+use std::net::SocketAddr;
+use serde_json::{ json, Value };
+use tokio::io::{ AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader };
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::{ debug, info };
+
+use blockchain::block::Block;
+use mempool::BlockTemplate;
+use model::VarStr;
+use utils::Error;
+use super::message::ServerMessage;
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes().chunks(2)
+        .map(|pair| if pair.len() == 2 { u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok() } else { None })
+        .collect()
+}
+
+// json-rpc server for external miners and wallets, one json request per line
+// estimatefee answers null when there is not enough data
+#[derive(Clone)]
+pub struct JsonRpc {
+    server_sender   : mpsc::Sender<ServerMessage>,
+    payout          : VarStr,
+}
+
+impl JsonRpc {
+    pub fn new(server_sender: mpsc::Sender<ServerMessage>, payout: VarStr) -> JsonRpc {
+        JsonRpc {
+            server_sender,
+            payout,
+        }
+    }
+
+    pub async fn listen(self, port: u16) -> Result<(), Error> {
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let mut listener = TcpListener::bind(&addr).await?;
+        info!("RPC server listening on port {}", port);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let rpc = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rpc.handle_client(stream).await {
+                    debug!("RPC client {} disconnected: {:?}", addr, e);
+                }
+            });
+        }
+    }
+
+    pub async fn handle_client<S>(self, stream: S) -> Result<(), Error>
+        where S: AsyncRead + AsyncWrite {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(request) => {
+                    let id = request["id"].clone();
+                    let params = request["params"].as_array().cloned().unwrap_or_default();
+                    match self.call(request["method"].as_str().unwrap_or(""), &params).await {
+                        Ok(result) => json!({ "id": id, "result": result, "error": Value::Null }),
+                        Err((code, message)) => error(id, code, message),
+                    }
+                },
+                Err(_) => error(Value::Null, -32700, "Parse error"),
+            };
+            let mut response = response.to_string();
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, params: &[Value]) -> Result<Value, (i32, &'static str)> {
+        match method {
+            "getblocktemplate" => {
+                let payout = match params.first().and_then(|v| v.as_str()) {
+                    Some(payout) => VarStr::from_string(payout.to_string()),
+                    None => self.payout.clone(),
+                };
+                let (sender, mut receiver) = mpsc::channel(1);
+                self.server_sender.clone().send(ServerMessage::GetBlockTemplate(sender, payout)).await
+                    .map_err(|_| (-32603, "Node unavailable"))?;
+                match receiver.recv().await {
+                    Some(ServerMessage::BlockTemplate(template)) => template_to_json(&template),
+                    _ => Err((-32603, "Could not build a block template")),
+                }
+            },
+            "submitblock" => {
+                let data = params.first().and_then(|v| v.as_str()).and_then(from_hex)
+                    .ok_or((-32602, "Invalid block data"))?;
+                let block = Block::read(&data).map_err(|_| (-32602, "Invalid block data"))?;
+                self.server_sender.clone().send(ServerMessage::MinedBlock(block)).await
+                    .map_err(|_| (-32603, "Node unavailable"))?;
+                Ok(Value::Null)
+            },
+            "estimatefee" => {
+                let target = params.first().and_then(|v| v.as_u64())
+                    .ok_or((-32602, "Invalid confirmation target"))?;
+                let (sender, mut receiver) = mpsc::channel(1);
+                self.server_sender.clone().send(ServerMessage::EstimateFee(sender, target as usize)).await
+                    .map_err(|_| (-32603, "Node unavailable"))?;
+                match receiver.recv().await {
+                    Some(ServerMessage::FeeEstimate(estimate)) => Ok(json!(estimate)),
+                    _ => Err((-32603, "Could not estimate fees")),
+                }
+            },
+            _ => Err((-32601, "Method not found")),
+        }
+    }
+}
+
+fn template_to_json(template: &BlockTemplate) -> Result<Value, (i32, &'static str)> {
+    let mut transactions = Vec::new();
+    // the coinbase is built by the node and paid to the payout script
+    for tx in template.transactions.iter().skip(1) {
+        let data = tx.send().map_err(|_| (-32603, "Could not serialize a transaction"))?;
+        let hash = tx.hash().map_err(|_| (-32603, "Could not hash a transaction"))?;
+        transactions.push(json!({
+            "data": utils::hash_to_string(&data),
+            "hash": utils::hash_to_string(&hash),
+        }));
+    }
+    let coinbase = template.transactions[0].send().map_err(|_| (-32603, "Could not serialize the coinbase"))?;
+    Ok(json!({
+        "version": template.version,
+        "previousblockhash": utils::hash_to_string(&template.previous_hash),
+        "height": template.height,
+        "curtime": template.timestamp,
+        "target": utils::hash_to_string(&template.difficulty),
+        "merkleroot": utils::hash_to_string(&template.merkle_root),
+        "coinbasetxn": { "data": utils::hash_to_string(&coinbase) },
+        "coinbasevalue": template.coinbase_value,
+        "fees": template.fees,
+        "transactions": transactions,
+    }))
+}
+
+fn error(id: Value, code: i32, message: &str) -> Value {
+    json!({ "id": id, "result": Value::Null, "error": { "code": code, "message": message } })
+}
@@ -1,9 +1,13 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use bincode::{serialize, deserialize};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use model::*;
 use blockchain::transaction::Transaction;
 use blockchain::block::Block;
+use mempool::BlockTemplate;
+use utils::Error;
 use utils::Size;
 use utils::ToBytes;
 
@@ -11,25 +15,94 @@ use utils::ToBytes;
 #[derive(Debug)]
 pub enum ServerMessage {
     CreatePeer(SocketAddr),
-    AddPeer(mpsc::Sender<ServerMessage>, SocketAddr),
+    AcceptPeer(TcpStream, SocketAddr),
+    // sender, ip, initiated by us, services and the address the peer listens on
+    AddPeer(mpsc::Sender<ServerMessage>, SocketAddr, bool, Vec<String>, Option<SocketAddr>),
     DeletePeer(SocketAddr),
 
     CheckBlocks(mpsc::Sender<ServerMessage>, Vec<Vec<u8>>),
     AskBlocks(Vec<(Vec<u8>, u32)>),
-    AddBlock(Block),
-
-    CheckTxs(mpsc::Sender<ServerMessage>, Vec<Vec<u8>>),
+    AddBlock(SocketAddr, Block),
+    MinedBlock(Block),
+    SendBlock(Block),
+    SendHeaders(SocketAddr),
+    AnnounceHeaders(Vec<Block>),
+    AddHeaders(mpsc::Sender<ServerMessage>, SocketAddr, Vec<Block>),
+    AskHeaders(Vec<Vec<u8>>),
+    GetHeaders(mpsc::Sender<ServerMessage>, GetBlocks),
+    GetHeadersReply(Vec<Block>),
+    SyncTick,
+
+    CheckTxs(mpsc::Sender<ServerMessage>, SocketAddr, Vec<Vec<u8>>),
     AskTxs(Vec<Vec<u8>>),
-    AddTx(Transaction),
-
-    GetBlocks(mpsc::Sender<ServerMessage>, GetBlocks),
+    AddTx(mpsc::Sender<ServerMessage>, SocketAddr, Transaction),
+    SendTx(Transaction),
+    Announce(Vec<(Vec<u8>, u32)>),
+    Trickle,
+
+    GetData(mpsc::Sender<ServerMessage>, SocketAddr, Vec<(Vec<u8>, u32)>),
+
+    ConnectPeers,
+    ConnectFailed(SocketAddr),
+    Misbehaving(SocketAddr, u32, String),
+    ListBanned(mpsc::Sender<ServerMessage>),
+    BannedList(Vec<(std::net::IpAddr, u64)>),
+    ClearBanned,
+    PingTick,
+    SendPing(u64),
+    Pong(SocketAddr, u64),
+    GetPeerInfo(mpsc::Sender<ServerMessage>),
+    SendMessage(Message),
+    UnknownMessage(SocketAddr, String),
+    NotFound(SocketAddr, Vec<(Vec<u8>, u32)>),
+    SendNotFound(Vec<(Vec<u8>, u32)>),
+    GetMempool(mpsc::Sender<ServerMessage>, SocketAddr),
+    // address, inbound, last and minimum round trip time of each peer
+    PeerInfo(Vec<(SocketAddr, bool, Option<std::time::Duration>, Option<std::time::Duration>)>),
+    GetAddr(mpsc::Sender<ServerMessage>),
+    AddAddrs(SocketAddr, Vec<Address>),
+    SendAddrs(Vec<Address>),
+
+    GetBlocks(mpsc::Sender<ServerMessage>, SocketAddr, GetBlocks),
     GetBlocksReply(Vec<(Vec<u8>, u32)>),
 
+    GetBlockTemplate(mpsc::Sender<ServerMessage>, VarStr),
+    BlockTemplate(BlockTemplate),
+
+    EstimateFee(mpsc::Sender<ServerMessage>, usize),
+    FeeEstimate(Option<u64>),
+
     CloseConnection,
     ClosePeer(SocketAddr),
     CloseServer,
 }
 
+// distinct unknown commands counted, later new ones are only logged
+const MAX_UNKNOWN_COMMANDS  : usize = 64;
+
+// counts the commands peers sent us which we don't understand
+#[derive(Default)]
+pub struct UnknownCommands {
+    counts  : HashMap<String, u64>,
+}
+
+impl UnknownCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns how many times the command was received, this one included, or None
+    // once too many different commands are counted
+    pub fn record(&mut self, name: &str) -> Option<u64> {
+        if !self.counts.contains_key(name) && self.counts.len() >= MAX_UNKNOWN_COMMANDS {
+            return None
+        }
+        let count = self.counts.entry(name.to_string()).or_insert(0);
+        *count += 1;
+        Some(*count)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum Message {
@@ -38,22 +111,74 @@ pub enum Message {
     Inv(Inv),
     GetData(Inv),
     GetBlocks(GetBlocks),
+    GetHeaders(GetBlocks),
+    Block(Block),
+    Transaction(Transaction),
+    SendHeaders,
+    Headers(Headers),
+    GetAddr,
+    Addr(Addr),
+    Ping(u64),
+    Pong(u64),
+    NotFound(Inv),
+    GetMempool,
     TwoPlusTwo,
     MinusOne,
+    Unknown(String),
 }
 
 impl Message {
+    // command name, padded with zeros to 12 bytes on the wire
     pub fn name(&self) -> &str {
         match self {
-            Message::WhoAmI(_)      => "whoami\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}",
-            Message::WhoAmIAck      => "whoamiack\u{0}\u{0}\u{0}",
-            Message::Inv(_)         => "inv\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}",
-            Message::GetData(_)     => "getdata\u{0}\u{0}\u{0}\u{0}\u{0}",
-            Message::GetBlocks(_)   => "getblocks\u{0}\u{0}\u{0}",
-            Message::TwoPlusTwo     => "2plus2is4\u{0}\u{0}\u{0}",
+            Message::WhoAmI(_)      => "whoami",
+            Message::WhoAmIAck      => "whoamiack",
+            Message::Inv(_)         => "inv",
+            Message::GetData(_)     => "getdata",
+            Message::GetBlocks(_)   => "getblocks",
+            Message::GetHeaders(_)  => "getheaders",
+            Message::Block(_)       => "block",
+            Message::Transaction(_) => "transaction",
+            Message::SendHeaders    => "sendheaders",
+            Message::Headers(_)     => "headers",
+            Message::GetAddr        => "getaddr",
+            Message::Addr(_)        => "addr",
+            Message::Ping(_)        => "ping",
+            Message::Pong(_)        => "pong",
+            Message::NotFound(_)    => "notfound",
+            Message::GetMempool     => "getmempool",
+            Message::TwoPlusTwo     => "2plus2is4",
             Message::MinusOne       => "minus1thats3",
+            Message::Unknown(name)  => name,
         }
     }
+
+    // decodes the payload of a message of type name
+    pub fn read(name: &str, payload: &[u8]) -> Result<Message, Error> {
+        let name = name.trim_end_matches('\u{0}');
+        let message = match name {
+            "whoami"        => Message::WhoAmI(WhoAmI::read(payload)?),
+            "whoamiack"     => Message::WhoAmIAck,
+            "inv"           => Message::Inv(Inv::read(payload)?),
+            "getdata"       => Message::GetData(Inv::read(payload)?),
+            "getblocks"     => Message::GetBlocks(GetBlocks::read(payload)?),
+            "getheaders"    => Message::GetHeaders(GetBlocks::read(payload)?),
+            "block"         => Message::Block(Block::read(payload)?),
+            "transaction"   => Message::Transaction(Transaction::read(payload)?),
+            "sendheaders"   => Message::SendHeaders,
+            "headers"       => Message::Headers(Headers::read(payload)?),
+            "getaddr"       => Message::GetAddr,
+            "addr"          => Message::Addr(Addr::read(payload)?),
+            "ping"          => Message::Ping(read_nonce(payload)?),
+            "pong"          => Message::Pong(read_nonce(payload)?),
+            "notfound"      => Message::NotFound(Inv::read(payload)?),
+            "getmempool"    => Message::GetMempool,
+            "2plus2is4"     => Message::TwoPlusTwo,
+            "minus1thats3"  => Message::MinusOne,
+            _               => Message::Unknown(name.to_string()),
+        };
+        Ok(message)
+    }
 }
 
 impl Size for Message {
@@ -63,6 +188,14 @@ impl Size for Message {
             Message::Inv(m)         => m.size(),
             Message::GetData(m)     => m.size(),
             Message::GetBlocks(m)   => m.size(),
+            Message::GetHeaders(m)  => m.size(),
+            Message::Block(m)       => m.size(),
+            Message::Transaction(m) => m.size(),
+            Message::Headers(m)     => m.size(),
+            Message::Addr(m)        => m.size(),
+            Message::Ping(_)        => 8,
+            Message::Pong(_)        => 8,
+            Message::NotFound(m)    => m.size(),
             _                       => 0,
         }
     }
@@ -75,39 +208,79 @@ impl ToBytes for Message {
             Message::Inv(m) => m.send(),
             Message::GetData(m) => m.send(),
             Message::GetBlocks(m) => m.send(),
+            Message::GetHeaders(m) => m.send(),
+            Message::Block(m) => m.send().unwrap(),
+            Message::Transaction(m) => m.send().unwrap(),
+            Message::Headers(m) => m.send(),
+            Message::Addr(m) => m.send(),
+            Message::Ping(nonce) | Message::Pong(nonce) => nonce.to_be_bytes().to_vec(),
+            Message::NotFound(m) => m.send(),
             _ => Vec::new(),
         }
     }
 }
 
+// what is left of buffer from offset, empty past its end
+fn rest(buffer: &[u8], offset: usize) -> &[u8] {
+    buffer.get(offset..).unwrap_or(&[])
+}
+
+fn read_hash(buffer: &[u8], offset: usize) -> Result<Vec<u8>, Error> {
+    match buffer.get(offset..offset + 32) {
+        Some(hash) => Ok(hash.to_vec()),
+        None => Err(Error::ParseError("hash out of the buffer".to_string())),
+    }
+}
+
 #[derive(Debug)]
 pub struct WhoAmI {
     pub version     : u32,
     from            : Address,
     service_count   : VarUint,
-    services        : VarStr,
+    services        : Vec<VarStr>,
 } impl WhoAmI {
-    pub fn new(version: u32) -> Self {
-        let mut m = Self::default();
-        m.version = version;
-        m
+    pub fn new(version: u32, from: &SocketAddr, services: &[&str]) -> Self {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        Self {
+            version,
+            from            : Address::from_socket_addr(from, now),
+            service_count   : VarUint::from_u64(services.len() as u64),
+            services        : services.iter().map(|s| VarStr::from_string(s.to_string())).collect(),
+        }
+    }
+
+    pub fn services(&self) -> Vec<String> {
+        self.services.iter().map(|s| s.value.clone()).collect()
     }
 
-    pub fn read(payload : Vec<u8>) -> Self {
+    // address the peer listens on
+    pub fn from(&self) -> SocketAddr {
+        self.from.socket_addr()
+    }
+
+    pub fn read(payload: &[u8]) -> Result<Self, Error> {
+        if payload.len() < 30 {
+            return Err(Error::ParseError("whoami message too short".to_string()))
+        }
         let mut version = payload[0..4].to_vec();
         version.reverse();
-        let version : u32 = deserialize(&version).unwrap();
+        let version : u32 = deserialize(&version)?;
 
         let address = Address::new(payload[4..30].to_vec());
-        let service_count = VarUint::new(&payload[30..].to_vec());
-        let size = service_count.size() as usize;
-        let services = VarStr::new(&payload[29+size..].to_vec());
-        Self {
+        let service_count = VarUint::read(&payload[30..])?;
+        let mut offset = 30 + service_count.size() as usize;
+        let mut services = Vec::new();
+        for _ in 0..service_count.value {
+            let service = VarStr::read(rest(payload, offset))?;
+            offset += service.size() as usize;
+            services.push(service);
+        }
+        Ok(Self {
             version,
             from: address,
             service_count,
             services,
-        }
+        })
     }
 }
 
@@ -120,25 +293,16 @@ impl ToBytes for WhoAmI {
         buffer.append(&mut version);
         buffer.append(&mut self.from.send());
         buffer.append(&mut self.service_count.send());
-        buffer.append(&mut self.services.send());
-        buffer
-    }
-}
-
-impl Default for WhoAmI {
-    fn default() -> Self {
-        Self {
-            version         : 0,
-            from            : Address::from_string("46.193.66.26".to_string()).unwrap(),
-            service_count   : VarUint::from_u64(1),
-            services        : VarStr::from_string("node".to_string())
+        for service in &self.services {
+            buffer.append(&mut service.send());
         }
+        buffer
     }
 }
 
 impl Size for WhoAmI {
     fn size(&self) -> u64 {
-        4 + self.from.size() + self.service_count.size() + self.services.size()
+        4 + self.from.size() + self.service_count.size() + self.services.iter().map(|s| s.size()).sum::<u64>()
     }
 }
 
@@ -163,21 +327,21 @@ impl Inv {
         }
     }
 
-    pub fn read(buffer: &[u8]) -> Inv {
-        let count = VarUint::new(buffer);
+    pub fn read(buffer: &[u8]) -> Result<Inv, Error> {
+        let count = VarUint::read(buffer)?;
         let mut offset : usize = count.size() as usize;
 
         let mut inventory = Vec::new();
         for _ in 0..count.value {
-            let inv = InvVect::read(&buffer[offset..].to_vec());
+            let inv = InvVect::read(rest(buffer, offset))?;
             offset += inv.size() as usize;
             inventory.push(inv);
         }
 
-        Inv {
+        Ok(Inv {
             count,
             inventory
-        }
+        })
     }
 }
 
@@ -198,6 +362,7 @@ impl Size for Inv {
     }
 }
 
+// getblocks or getheaders message
 #[derive(Debug)]
 pub struct GetBlocks {
     pub count           : VarUint,
@@ -214,22 +379,22 @@ impl GetBlocks {
         }
     }
 
-    pub fn read(buffer: &[u8]) -> GetBlocks {
-        let count = VarUint::new(buffer);
+    pub fn read(buffer: &[u8]) -> Result<GetBlocks, Error> {
+        let count = VarUint::read(buffer)?;
         let mut offset : usize = count.size() as usize;
 
         let mut block_locator = Vec::new();
         for _ in 0..count.value {
-            block_locator.push(buffer[offset..offset+32].to_vec());
+            block_locator.push(read_hash(buffer, offset)?);
             offset += 32;
         }
-        let hash_stop = buffer[offset..offset+32].to_vec();
+        let hash_stop = read_hash(buffer, offset)?;
 
-        GetBlocks {
+        Ok(GetBlocks {
             count,
             block_locator,
             hash_stop
-        }
+        })
     }
 }
 
@@ -253,3 +418,166 @@ impl Size for GetBlocks {
         self.count.size() + 32 * self.block_locator.len() as u64 + 32
     }
 }
+
+// headers message, each header is sent as a block without transactions
+#[derive(Debug)]
+pub struct Headers {
+    pub count   : VarUint,
+    pub headers : Vec<Block>,
+}
+impl Headers {
+
+    pub fn from_blocks(blocks: Vec<Block>) -> Self {
+        let headers : Vec<Block> = blocks.into_iter().map(|mut b| {
+            b.transactions = Vec::new();
+            b
+        }).collect();
+        Self {
+            count: VarUint::from_u64(headers.len() as u64),
+            headers,
+        }
+    }
+
+    pub fn read(buffer: &[u8]) -> Result<Headers, Error> {
+        let count = VarUint::read(buffer)?;
+        let mut offset : usize = count.size() as usize;
+
+        let mut headers = Vec::new();
+        for _ in 0..count.value {
+            let header = Block::read(rest(buffer, offset))?;
+            offset += header.size() as usize;
+            headers.push(header);
+        }
+
+        Ok(Headers {
+            count,
+            headers
+        })
+    }
+}
+
+impl ToBytes for Headers {
+    fn send(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.append(&mut self.count.send());
+        for header in &self.headers {
+            buffer.append(&mut header.send().unwrap());
+        }
+        buffer
+    }
+}
+
+impl Size for Headers {
+    fn size(&self) -> u64 {
+        self.count.size() + self.headers.iter().map(|h| h.size()).sum::<u64>()
+    }
+}
+
+#[derive(Debug)]
+pub struct Addr {
+    pub count       : VarUint,
+    pub addresses   : Vec<Address>,
+}
+impl Addr {
+
+    pub fn from_vec(addresses: Vec<Address>) -> Self {
+        Self {
+            count: VarUint::from_u64(addresses.len() as u64),
+            addresses,
+        }
+    }
+
+    pub fn read(buffer: &[u8]) -> Result<Addr, Error> {
+        let count = VarUint::read(buffer)?;
+        let mut offset : usize = count.size() as usize;
+
+        let mut addresses = Vec::new();
+        for _ in 0..count.value {
+            let address = match buffer.get(offset..offset + 26) {
+                Some(address) => Address::new(address.to_vec()),
+                None => return Err(Error::ParseError("address out of the buffer".to_string())),
+            };
+            offset += address.size() as usize;
+            addresses.push(address);
+        }
+
+        Ok(Addr {
+            count,
+            addresses
+        })
+    }
+}
+
+impl ToBytes for Addr {
+    fn send(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.append(&mut self.count.send());
+        for address in &self.addresses {
+            buffer.append(&mut address.send());
+        }
+        buffer
+    }
+}
+
+impl Size for Addr {
+    fn size(&self) -> u64 {
+        self.count.size() + 26 * self.addresses.len() as u64
+    }
+}
+
+// nonce of a ping or pong message
+fn read_nonce(payload: &[u8]) -> Result<u64, Error> {
+    if payload.len() < 8 {
+        return Err(Error::ParseError("ping or pong without a nonce".to_string()))
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&payload[0..8]);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_payloads_rejected() {
+        let mut inv = vec![2];
+        inv.extend_from_slice(&[0; 36]);
+        let mut getblocks = vec![1];
+        getblocks.extend_from_slice(&[0; 40]);
+        let mut addr = vec![2];
+        addr.extend_from_slice(&[0; 26 + 10]);
+        for (name, payload) in &[
+            ("inv", vec![]),
+            ("inv", inv),
+            ("getblocks", getblocks),
+            ("headers", vec![0xFD, 1]),
+            ("addr", vec![]),
+            ("addr", addr),
+            ("transaction", vec![0; 5]),
+            ("block", vec![0; 10]),
+        ] {
+            assert!(Message::read(name, payload).is_err(), "{} accepted", name);
+        }
+    }
+
+    #[test]
+    fn addr_shorter_than_its_count_rejected() {
+        let from = "127.0.0.1:4224".parse().unwrap();
+        let addr = Addr::from_vec(vec![Address::from_socket_addr(&from, 1), Address::from_socket_addr(&from, 2)]);
+        let mut payload = addr.send();
+        assert_eq!(Addr::read(&payload).unwrap().addresses.len(), 2);
+        payload.truncate(payload.len() - 1);
+        assert!(Addr::read(&payload).is_err());
+    }
+
+    #[test]
+    fn unknown_commands_capped() {
+        let mut unknown = UnknownCommands::new();
+        for i in 0..MAX_UNKNOWN_COMMANDS {
+            assert_eq!(unknown.record(&i.to_string()), Some(1));
+        }
+        assert_eq!(unknown.record("0"), Some(2));
+        assert_eq!(unknown.record("new"), None);
+    }
+}
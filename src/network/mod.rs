@@ -1,9 +1,19 @@
 pub mod server;
 pub mod peer;
-pub mod known_peers;
+pub mod addrman;
+pub mod codec;
+pub mod banman;
+pub mod connman;
+pub mod ping;
+pub mod eviction;
+pub mod jsonrpc;
 pub mod message;
+pub mod stratum;
+pub mod relay;
+pub mod sync;
 
-pub use self::known_peers::KnownPeers;
+pub use self::addrman::AddrMan;
 pub use self::peer::Peer;
 pub use self::server::Server;
 pub use self::message::*;
+pub use self::stratum::Stratum;
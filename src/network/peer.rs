@@ -1,18 +1,18 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::atomic::AtomicU32;
-use tokio::io::AsyncWrite;
+use std::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
 use std::cmp::min;
-use bincode::serialize;
-use bincode::deserialize;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use futures::SinkExt;
+use super::codec::MessageCodec;
 use super::message::*;
 use tokio::net::TcpStream;
-use tokio::io::{ AsyncWriteExt, AsyncReadExt };
+use tokio::net::tcp::{ OwnedReadHalf, OwnedWriteHalf };
+use tokio::stream::StreamExt;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+use tokio_util::codec::{ FramedRead, FramedWrite };
 use tracing::{ debug, error, info, span, warn };
 use utils::Error;
-use utils::Size;
-use utils::ToBytes;
 
 #[derive(PartialEq, Debug)]
 enum State {
@@ -21,280 +21,335 @@ enum State {
     Acknowledged
 }
 
-type Locked<T> = Arc<Mutex<T>>;
+// most addresses accepted in a single addr message
+pub const MAX_ADDR : usize = 1000;
+pub const MAX_INV  : usize = 50_000;
+
+// peers have this long after the connection to complete the handshake
+pub const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs(60);
+
+pub const PROTOCOL_VERSION : u32 = 2;
+// from this version on, message headers end with a checksum of the payload
+pub const CHECKSUM_VERSION : u32 = 2;
+
+// services advertised in whoami: node relays transactions and blocks, full-history serves
+// every block of the chain, bloom-filter serves filtered blocks
+pub const SERVICE_NODE          : &str = "node";
+pub const SERVICE_FULL_HISTORY  : &str = "full-history";
+pub const SERVICE_BLOOM_FILTER  : &str = "bloom-filter";
+pub const SERVICES              : [&str; 2] = [SERVICE_NODE, SERVICE_FULL_HISTORY];
+
+// services of the peer we know how to use, nodes older than the version 2 only advertise
+// node while keeping every block
+fn negotiate_services(advertised: Vec<String>, version: u32) -> Vec<String> {
+    let mut services : Vec<String> = advertised.into_iter()
+        .filter(|s| [SERVICE_NODE, SERVICE_FULL_HISTORY, SERVICE_BLOOM_FILTER].contains(&s.as_str()))
+        .collect();
+    services.sort();
+    services.dedup();
+    if version < PROTOCOL_VERSION && services.iter().any(|s| s == SERVICE_NODE)
+        && !services.iter().any(|s| s == SERVICE_FULL_HISTORY) {
+        services.push(SERVICE_FULL_HISTORY.to_string());
+    }
+    services
+}
 
 pub struct Peer {
-    stream              : Locked<TcpStream>,
+    reader              : FramedRead<OwnedReadHalf, MessageCodec>,
     server_sender       : mpsc::Sender<ServerMessage>,
     sender              : mpsc::Sender<ServerMessage>,
     connection_version  : Arc<AtomicU32>,
+    // set once the handshake is done if both sides checksum their messages
+    checksum            : Arc<AtomicBool>,
     initiated_by_us     : bool,
-    connection_state    : Locked<State>,
-    peer_addr           : String,
+    connection_state    : State,
+    peer_addr           : SocketAddr,
+    // address we advertise to the peer
+    self_addr           : SocketAddr,
+    services            : Vec<String>,
+    // address the peer advertised in its whoami
+    listen_addr         : Option<SocketAddr>,
 } impl Peer {
-    pub fn new(stream : TcpStream, server_sender : mpsc::Sender<ServerMessage>, initiated_by_us : bool) -> Peer {
+    pub fn new(stream : TcpStream, server_sender : mpsc::Sender<ServerMessage>, initiated_by_us : bool, self_addr : SocketAddr, max_message_size : u64) -> Peer {
         let (sender, receiver) = mpsc::channel(512);
         let ip = stream.peer_addr().unwrap();
-        let stream = Arc::new(Mutex::new(stream));
-        let s2 = stream.clone();
-        tokio::spawn(async move { Peer::handle_server_message(receiver, &s2).await.unwrap(); });
+        // reads and writes go through separate halves so neither waits on the other
+        let (read, write) = stream.into_split();
+        let checksum = Arc::new(AtomicBool::new(false));
+        let writer = FramedWrite::new(write, MessageCodec::new(checksum.clone(), max_message_size));
+        tokio::spawn(async move {
+            if let Err(e) = Peer::handle_server_message(receiver, writer).await {
+                debug!("Writing to {} stopped: {:?}", ip, e);
+            }
+        });
         Peer {
-            stream,
+            reader              : FramedRead::new(read, MessageCodec::new(checksum.clone(), max_message_size)),
             server_sender,
             sender,
-            connection_version  : Arc::new(AtomicU32::new(1)),
+            connection_version  : Arc::new(AtomicU32::new(PROTOCOL_VERSION)),
+            checksum,
             initiated_by_us,
-            connection_state    : Arc::new(Mutex::new(State::Tcp)),
-            peer_addr           : ip.to_string(),
+            connection_state    : State::Tcp,
+            peer_addr           : ip,
+            self_addr,
+            services            : Vec::new(),
+            listen_addr         : None,
         }
     }
 
     pub async fn read_message(&mut self) -> Result<(), Error> {
-        let ip = self.peer_addr.clone();
+        let ip = self.peer_addr.to_string();
         let span = span!(tracing::Level::DEBUG, "Reading message", ip = ip.as_str());
         let _enter = span.enter();
-        let stream = self.stream.clone();
-        let (message_type, payload) = self.check_header(&stream).await?;
-        let state = self.connection_state.clone();
-        let state = state.lock().await;
-        if *state != State::Acknowledged {
-            self.handle_handshake(message_type, payload, state, &stream).await?;
+        let message = match self.reader.next().await {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                match e {
+                    Error::WrongMagic => self.misbehaving(100, "wrong magic number").await?,
+                    Error::MessageTooLarge => self.misbehaving(20, "oversized message").await?,
+                    Error::WrongChecksum => self.misbehaving(10, "wrong checksum").await?,
+                    _ => (),
+                }
+                return Err(e)
+            },
+            None => return Err(Error::ConnectionClosed),
+        };
+        if self.connection_state != State::Acknowledged {
+            return self.handle_handshake(message).await
         }
-        else {
-            drop(state);
-            match message_type.as_str() {
-                "2plus2is4\u{0}\u{0}\u{0}" => {
-                    debug!(ip = ip.as_str(), "2 plus 2 is 4!");
-                    let message = Message::MinusOne;
-                    Peer::send(message, &stream).await?;
-                },
-                "inv\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}" => {
-                    let message = Inv::read(&payload);
-                    debug!("Received Inv message with {} items", &message.count.value);
-                    let mut txs = Vec::new();
-                    let mut blocks = Vec::new();
-                    for item in message.inventory {
-                        if item.hash_type == 0 { //TX
-                            txs.push(item.hash);
-                        } else {
-                            blocks.push(item.hash);
-                        }
-                    }
-                    if !txs.is_empty() {
-                        self.server_sender.send(ServerMessage::CheckTxs(self.sender.clone(), txs)).await?;
-                    }
-                    if !blocks.is_empty() {
-                        self.server_sender.send(ServerMessage::CheckBlocks(self.sender.clone(), blocks)).await?;
+
+        match message {
+            Message::TwoPlusTwo => {
+                debug!(ip = ip.as_str(), "2 plus 2 is 4!");
+                self.send(Message::MinusOne).await?;
+            },
+            Message::Inv(message) => {
+                debug!("Received Inv message with {} items", &message.count.value);
+                if message.inventory.len() > MAX_INV {
+                    self.misbehaving(20, "inv message with too many items").await?;
+                    return Ok(())
+                }
+                let mut txs = Vec::new();
+                let mut blocks = Vec::new();
+                for item in message.inventory {
+                    if item.hash_type == 0 { //TX
+                        txs.push(item.hash);
+                    } else {
+                        blocks.push(item.hash);
                     }
-                },
-                "getdata\u{0}\u{0}\u{0}\u{0}\u{0}" => {
-                    let message = Message::GetData(Inv::read(&payload));
-                    debug!("{:?}", &message);
-                },
-                "getblocks\u{0}\u{0}\u{0}" => {
-                    debug!("Received getblocks");
-                    let message = GetBlocks::read(&payload);
-                    self.server_sender.send(ServerMessage::GetBlocks(self.sender.clone(), message)).await?;
-                },
-                "transaction\u{0}" => {
-                    let tx = blockchain::transaction::Transaction::read(&payload);
-                    info!("Received tx, tx_hash: {}", utils::hash_to_string(&tx.hash().unwrap()));
-                    self.server_sender.send(ServerMessage::AddTx(tx)).await?;
-                },
-                "block\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}" => {
-                    let block = blockchain::block::Block::read(&payload)?;
-                    info!("Received block, block_hash: {}", utils::hash_to_string(&block.hash().unwrap()));
-                    self.server_sender.send(ServerMessage::AddBlock(block)).await?;
-                },
-                _ => { warn!("didn't understand message type: {}", message_type); }
-            }
+                }
+                if !txs.is_empty() {
+                    self.server_sender.send(ServerMessage::CheckTxs(self.sender.clone(), self.peer_addr, txs)).await?;
+                }
+                if !blocks.is_empty() {
+                    self.server_sender.send(ServerMessage::CheckBlocks(self.sender.clone(), blocks)).await?;
+                }
+            },
+            Message::GetData(message) => {
+                debug!("Received getdata with {} items", &message.count.value);
+                let items = message.inventory.into_iter().map(|i| (i.hash, i.hash_type)).collect();
+                self.server_sender.send(ServerMessage::GetData(self.sender.clone(), self.peer_addr, items)).await?;
+            },
+            Message::GetBlocks(message) => {
+                debug!("Received getblocks");
+                self.server_sender.send(ServerMessage::GetBlocks(self.sender.clone(), self.peer_addr, message)).await?;
+            },
+            Message::Transaction(tx) => {
+                info!("Received tx, tx_hash: {}", utils::hash_to_string(&tx.hash().unwrap()));
+                self.server_sender.send(ServerMessage::AddTx(self.sender.clone(), self.peer_addr, tx)).await?;
+            },
+            Message::Block(block) => {
+                info!("Received block, block_hash: {}", utils::hash_to_string(&block.hash().unwrap()));
+                self.server_sender.send(ServerMessage::AddBlock(self.peer_addr, block)).await?;
+            },
+            Message::SendHeaders => {
+                debug!("Peer prefers headers announcements");
+                self.server_sender.send(ServerMessage::SendHeaders(self.peer_addr)).await?;
+            },
+            Message::Headers(message) => {
+                debug!("Received {} headers", &message.count.value);
+                self.server_sender.send(ServerMessage::AddHeaders(self.sender.clone(), self.peer_addr, message.headers)).await?;
+            },
+            Message::GetAddr => {
+                debug!("Received getaddr");
+                self.server_sender.send(ServerMessage::GetAddr(self.sender.clone())).await?;
+            },
+            Message::Addr(message) => {
+                debug!("Received {} addresses", &message.count.value);
+                if message.addresses.len() > MAX_ADDR {
+                    self.misbehaving(20, "addr message with too many addresses").await?;
+                    return Ok(())
+                }
+                self.server_sender.send(ServerMessage::AddAddrs(self.peer_addr, message.addresses)).await?;
+            },
+            Message::Ping(nonce) => {
+                self.send(Message::Pong(nonce)).await?;
+            },
+            Message::Pong(nonce) => {
+                self.server_sender.send(ServerMessage::Pong(self.peer_addr, nonce)).await?;
+            },
+            Message::GetHeaders(message) => {
+                debug!("Received getheaders");
+                self.server_sender.send(ServerMessage::GetHeaders(self.sender.clone(), message)).await?;
+            },
+            Message::NotFound(message) => {
+                debug!("Received notfound with {} items", &message.count.value);
+                let items = message.inventory.into_iter().map(|i| (i.hash, i.hash_type)).collect();
+                self.server_sender.send(ServerMessage::NotFound(self.peer_addr, items)).await?;
+            },
+            Message::GetMempool => {
+                debug!("Received getmempool");
+                self.server_sender.send(ServerMessage::GetMempool(self.sender.clone(), self.peer_addr)).await?;
+            },
+            Message::Unknown(name) => {
+                self.server_sender.send(ServerMessage::UnknownMessage(self.peer_addr, name)).await?;
+            },
+            message => { warn!("unexpected message type: {}", message.name()); }
         }
         Ok(())
     }
 
     pub async fn update(mut self) -> Result<(), Error> {
-
+        let handshake_deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
         loop {
-            match self.read_message().await {
-                Ok(_) => (),
-                Err(e) => {
-                    let ip = self.peer_addr.clone();
-                    let span = span!(tracing::Level::ERROR, "Peer update loop ", ip = ip.as_str());
-                    let _enter = span.enter();
-                    match e {
-                    Error::IOError(e) => {
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
-                        } else {
-                            error!("{:?}", e); break;
-                        }
-                    },
-                    _ => { error!("{:?}", e); break; }
-                    }
+            let result = if self.connection_state == State::Acknowledged {
+                self.read_message().await
+            } else {
+                tokio::time::timeout_at(handshake_deadline, self.read_message()).await
+                    .unwrap_or(Err(Error::HandshakeTimeout))
+            };
+            if let Err(e) = result {
+                let ip = self.peer_addr.to_string();
+                let span = span!(tracing::Level::ERROR, "Peer update loop ", ip = ip.as_str());
+                let _enter = span.enter();
+                match e {
+                    Error::ConnectionClosed => info!("connection closed"),
+                    Error::HandshakeTimeout => info!("handshake timed out"),
+                    _ => error!("{:?}", e),
                 }
+                break;
             }
         }
-        self.close().await?;
-        Ok(())
+        self.close().await
     }
 
-    pub async fn connect(self) -> Result<(), Error> {
-        {
-            let message = Message::WhoAmI(WhoAmI::default());
-            Peer::send(message, &self.stream).await?;
-        }
-        self.update().await?;
-        Ok(())
-    }
-
-    async fn close(&mut self) -> Result<(), Error>{
-        self.server_sender.send(ServerMessage::DeletePeer(self.stream.lock().await.peer_addr().unwrap())).await.unwrap();
-        self.stream.lock().await.shutdown(std::net::Shutdown::Both)?;
-        Err(Error::ConnectionClosed)
+    pub async fn connect(mut self) -> Result<(), Error> {
+        self.send(Message::WhoAmI(WhoAmI::new(PROTOCOL_VERSION, &self.self_addr, &SERVICES))).await?;
+        self.update().await
     }
 
-    fn prepare_header(message_type : &str, size : u64) -> Result<Vec<u8>, Error> {
-        let mut buffer = Vec::new();
-        let magic : u32 = 42_2021; ////////////////// magic number
-        let mut magic = serialize(&magic)?;
-        magic.reverse();
-        buffer.append(&mut magic);
-        buffer.append(&mut message_type.as_bytes().to_vec());
-        if message_type.len() < 12 {
-            buffer.append(&mut vec![0; 12-message_type.len()]);
-        }
-        let mut size = serialize(&size)?;
-        size.reverse();
-        buffer.append(&mut size);
-        Ok(buffer)
-    }
-
-    async fn send<T>(message : Message, stream: &Locked<T>) -> Result<(), Error>
-        where T: AsyncWrite + std::marker::Unpin {
-        let mut stream = stream.lock().await;
-        let mut buffer = Peer::prepare_header(message.name(), message.size())?;
-        buffer.append(&mut message.send());
-        stream.write_all(&buffer).await?;
+    // messages are written by the task owning the write half
+    async fn send(&mut self, message: Message) -> Result<(), Error> {
+        self.sender.send(ServerMessage::SendMessage(message)).await?;
         Ok(())
     }
 
-    async fn read_header(&mut self, stream: &Locked<TcpStream>) -> Result<Vec<u8>, Error> {
-        let mut buffer : [u8; 24] = [0; 24];
-        let mut stream = stream.lock().await;
-        stream.read_exact(&mut buffer).await?;
-        Ok(buffer.to_vec())
-    }
-
-    async fn read_payload(&mut self, length : usize, stream: &Locked<TcpStream>) -> Result<Vec<u8>, Error> {
-        if length != 0 {
-            let mut buffer = vec![0; length];
-            let mut stream = stream.lock().await;
-            stream.read_exact(&mut buffer).await?;
-
-            Ok(buffer)
-        } else {
-            Ok(vec![])
-        }
+    async fn misbehaving(&mut self, howmuch: u32, reason: &str) -> Result<(), Error> {
+        self.server_sender.send(ServerMessage::Misbehaving(self.peer_addr, howmuch, reason.to_string())).await?;
+        Ok(())
     }
 
-    async fn check_header(&mut self, stream: &Locked<TcpStream>) -> Result<(String, Vec<u8>), Error> {
-        let header = self.read_header(stream).await?;
-        let mut magic = header[0..4].to_vec();
-        magic.reverse();
-        let magic : u32 = deserialize(&magic)?;
-        if magic != 42_2021 {
-            error!("wrong magic number : {}", magic);
-            return Err(Error::ConnectionClosed)
-        }
-        let message_type : String = String::from_utf8(header[4..16].to_vec())?;
-        let mut length = header[16..24].to_vec();
-        length.reverse();
-        let length : u64 = deserialize(&length)?;
-        let payload = self.read_payload(length as usize, stream).await?;
-        Ok((message_type, payload))
+    async fn close(&mut self) -> Result<(), Error> {
+        self.server_sender.send(ServerMessage::DeletePeer(self.peer_addr)).await?;
+        // the write task may already be gone
+        self.sender.send(ServerMessage::CloseConnection).await.ok();
+        Ok(())
     }
 
-    async fn handle_handshake(&mut self, message_type: String, payload: Vec<u8>, mut state: tokio::sync::MutexGuard<'_, State>, stream: &Locked<TcpStream>) -> Result<(), Error> {
-        match message_type.as_str() {
-            "whoami\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}" => {
-                if *state == State::Tcp {
+    async fn handle_handshake(&mut self, message: Message) -> Result<(), Error> {
+        match message {
+            Message::WhoAmI(message) => {
+                if self.connection_state == State::Tcp {
                     debug!("Received message whoami");
-                    let message = WhoAmI::read(payload);
                     let message_ver = message.version;
-                    let conn_ver = self.connection_version.load(std::sync::atomic::Ordering::Acquire);
+                    self.services = negotiate_services(message.services(), message_ver);
+                    debug!("Peer listens on {} with services {:?}", message.from(), self.services);
+                    // a peer not knowing its own ip listens on the one it connected from
+                    let from = message.from();
+                    let ip = if from.ip().is_unspecified() { self.peer_addr.ip() } else { from.ip() };
+                    self.listen_addr = if from.port() == 0 { None } else { Some(SocketAddr::new(ip, from.port())) };
+                    let conn_ver = self.connection_version.load(Ordering::Acquire);
                     if !self.initiated_by_us {
                         // send WhoAmI
-                        let message = Message::WhoAmI(WhoAmI::new(conn_ver));
-                        Peer::send(message, stream).await?;
+                        self.send(Message::WhoAmI(WhoAmI::new(conn_ver, &self.self_addr, &SERVICES))).await?;
                     }
-                    Peer::send(Message::WhoAmIAck, stream).await?;
-                    self.connection_version.store(min(message_ver, conn_ver),
-                        std::sync::atomic::Ordering::Release);
-                    *state = State::WhoAmI;
+                    self.connection_version.store(min(message_ver, conn_ver), Ordering::Release);
+                    self.send(Message::WhoAmIAck).await?;
+                    self.connection_state = State::WhoAmI;
                 } else {
                     error!("received unusual number of whoami");
+                    self.misbehaving(10, "unexpected whoami").await?;
                     return Err(Error::ConnectionClosed)
                 }
             },
-            "whoamiack\u{0}\u{0}\u{0}" => {
-                if *state == State::WhoAmI {
-                    *state = State::Acknowledged;
-                    let stream_locked = stream.lock().await;
-                    self.server_sender.send(ServerMessage::AddPeer(self.sender.clone(), stream_locked.peer_addr().unwrap())).await?;
+            Message::WhoAmIAck => {
+                if self.connection_state == State::WhoAmI {
+                    self.connection_state = State::Acknowledged;
+                    if self.connection_version.load(Ordering::Acquire) >= CHECKSUM_VERSION {
+                        self.checksum.store(true, Ordering::Release);
+                    }
+                    self.server_sender.send(ServerMessage::AddPeer(self.sender.clone(), self.peer_addr, self.initiated_by_us, self.services.clone(), self.listen_addr)).await?;
                     debug!("Handshake completed");
-                    drop(stream_locked);
-                    info!("Asking blocks");
-                    let getblocks = Message::GetBlocks(GetBlocks::from_hashes(vec![blockchain::Block::genesis_block()?.hash_header()?], vec![0;32]));
-                    Peer::send(getblocks, stream).await?;
+                    self.send(Message::SendHeaders).await?;
+                    // tell the peer where we can be reached, once per connection
+                    if self.self_addr.port() != 0 && !self.self_addr.ip().is_unspecified() {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        let address = model::Address::from_socket_addr(&self.self_addr, now);
+                        self.send(Message::Addr(Addr::from_vec(vec![address]))).await?;
+                    }
+                    if self.initiated_by_us {
+                        self.send(Message::GetAddr).await?;
+                    }
                 } else {
                     error!("reveiced whoamiack message before whoami message");
+                    self.misbehaving(10, "whoamiack before whoami").await?;
                     return Err(Error::ConnectionClosed)
                 }
             },
-            _ => {
-                error!("Recieved incorrect message_type : {:?}", message_type.as_str());
+            message => {
+                error!("Recieved incorrect message_type : {:?}", message.name());
+                self.misbehaving(10, "message before the handshake").await?;
                 return Err(Error::ConnectionClosed)
             }
         }
         Ok(())
     }
 
-    async fn handle_server_message(mut receiver: tokio::sync::mpsc::Receiver<ServerMessage>, stream: &Locked<TcpStream>) -> Result<(), Error> {
-        loop {
-            match receiver.recv().await {
-                Some(m)    => {
-                    match m {
-                        ServerMessage::CloseConnection  => {
-                            receiver.close();
-                            stream.lock().await.shutdown(std::net::Shutdown::Both)?;
-                            break;
-                        },
-                        ServerMessage::AskTxs(hashes)   => {
-                            //construct invvect and send it
-                            let mut inventory = Vec::new();
-                            let length = hashes.len();
-                            for hash in hashes {
-                                inventory.push(model::InvVect::from_vec(hash, 0));
-                            }
-                            let message = Message::Inv(Inv {
-                                count: model::VarUint::from_u64(length as u64),
-                                inventory
-                            });
-                            Peer::send(message, stream).await.unwrap();
-                        },
-                        ServerMessage::GetBlocksReply(hashs) => {
-                            let message = Message::Inv(Inv::from_vec(hashs));
-                            Peer::send(message, stream).await.unwrap();
-                        },
-                        ServerMessage::AskBlocks(hashs) => {
-                            debug!("Asking blocks");
-                            let message = Message::GetData(Inv::from_vec(hashs));
-                            Peer::send(message, stream).await.unwrap();
-                        },
-                        _   => ()
-                    }
+    async fn handle_server_message(mut receiver: mpsc::Receiver<ServerMessage>, mut writer: FramedWrite<OwnedWriteHalf, MessageCodec>) -> Result<(), Error> {
+        while let Some(m) = receiver.recv().await {
+            let message = match m {
+                ServerMessage::CloseConnection  => {
+                    receiver.close();
+                    writer.get_ref().as_ref().shutdown(std::net::Shutdown::Both)?;
+                    break;
                 },
-                None  =>  break,
-            }
+                ServerMessage::SendMessage(message) => message,
+                ServerMessage::AskTxs(hashes)   => {
+                    //construct invvect and send it
+                    let inventory = hashes.into_iter().map(|hash| (hash, 0)).collect();
+                    Message::GetData(Inv::from_vec(inventory))
+                },
+                ServerMessage::GetBlocksReply(hashs) => Message::Inv(Inv::from_vec(hashs)),
+                ServerMessage::AskBlocks(hashs) => {
+                    debug!("Asking blocks");
+                    Message::GetData(Inv::from_vec(hashs))
+                },
+                ServerMessage::Announce(hashs) => Message::Inv(Inv::from_vec(hashs)),
+                ServerMessage::SendAddrs(addresses) => Message::Addr(Addr::from_vec(addresses)),
+                ServerMessage::SendTx(tx) => Message::Transaction(tx),
+                ServerMessage::AskHeaders(locator) => {
+                    info!("Asking headers");
+                    Message::GetHeaders(GetBlocks::from_hashes(locator, vec![0; 32]))
+                },
+                ServerMessage::AnnounceHeaders(blocks) | ServerMessage::GetHeadersReply(blocks) => {
+                    Message::Headers(Headers::from_blocks(blocks))
+                },
+                ServerMessage::SendPing(nonce) => Message::Ping(nonce),
+                ServerMessage::SendNotFound(hashs) => Message::NotFound(Inv::from_vec(hashs)),
+                ServerMessage::SendBlock(block) => Message::Block(block),
+                _   => continue,
+            };
+            writer.send(message).await?;
         }
         Ok(())
     }
@@ -0,0 +1,64 @@
+use std::time::{ Duration, Instant };
+use rand::Rng;
+
+// how often peers are pinged and how long they have to answer
+pub const PING_INTERVAL     : Duration  = Duration::from_secs(2 * 60);
+pub const PING_TIMEOUT      : Duration  = Duration::from_secs(20 * 60);
+pub const PING_TICK         : Duration  = Duration::from_secs(1);
+
+// liveness and latency of a peer
+#[derive(Default)]
+pub struct PingState {
+    // nonce of the ping waiting for its pong and when it was sent
+    pending         : Option<(u64, Instant)>,
+    last_sent       : Option<Instant>,
+    pub last_ping   : Option<Duration>,
+    pub min_ping    : Option<Duration>,
+}
+
+impl PingState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // nonce of a new ping to send when the previous one was answered long enough ago
+    pub fn next_ping(&mut self, now: Instant) -> Option<u64> {
+        if self.pending.is_some() {
+            return None
+        }
+        if let Some(last_sent) = self.last_sent {
+            if last_sent + PING_INTERVAL > now {
+                return None
+            }
+        }
+        // a zero nonce would be indistinguishable from an unset one
+        let nonce = rand::thread_rng().gen_range(1, u64::MAX);
+        self.pending = Some((nonce, now));
+        self.last_sent = Some(now);
+        Some(nonce)
+    }
+
+    // the peer answered, returns the round trip time if the nonce is the expected one
+    pub fn pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some((expected, sent)) if expected == nonce => {
+                let latency = now - sent;
+                self.pending = None;
+                self.last_ping = Some(latency);
+                self.min_ping = Some(match self.min_ping {
+                    Some(min) => std::cmp::min(min, latency),
+                    None => latency,
+                });
+                Some(latency)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        match self.pending {
+            Some((_, sent)) => sent + PING_TIMEOUT < now,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{ HashSet, VecDeque };
+use std::time::{ Duration, Instant };
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+// how many inventory hashes are remembered for each peer
+const KNOWN_INVENTORY       : usize     = 5000;
+const MAX_ANNOUNCEMENTS     : usize     = 1000;
+// mean delay between two transaction announcements to a peer
+pub const INBOUND_TRICKLE   : Duration  = Duration::from_secs(5);
+pub const OUTBOUND_TRICKLE  : Duration  = Duration::from_secs(2);
+pub const TRICKLE_TICK      : Duration  = Duration::from_millis(500);
+// addresses younger than ADDR_FRESHNESS seconds, received in small addr messages, are
+// forwarded to ADDR_RELAY_PEERS random peers
+pub const ADDR_FRESHNESS    : u64       = 10 * 60;
+pub const MAX_ADDR_RELAY    : usize     = 10;
+pub const ADDR_RELAY_PEERS  : usize     = 2;
+
+// bounded set of hashes a peer already knows about, oldest ones are forgotten first
+#[derive(Default)]
+pub struct KnownInventory {
+    set     : HashSet<Vec<u8>>,
+    order   : VecDeque<Vec<u8>>,
+}
+
+impl KnownInventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.set.contains(hash)
+    }
+
+    // returns false when the hash was already known
+    pub fn insert(&mut self, hash: Vec<u8>) -> bool {
+        if !self.set.insert(hash.clone()) {
+            return false
+        }
+        self.order.push_back(hash);
+        if self.order.len() > KNOWN_INVENTORY {
+            let oldest = self.order.pop_front().unwrap();
+            self.set.remove(&oldest);
+        }
+        true
+    }
+}
+
+pub struct PeerRelay {
+    pub known       : KnownInventory,
+    pub known_addrs : KnownInventory,
+    // transactions we asked the peer for, others are unsolicited
+    pub requested   : KnownInventory,
+    // the peer asked for new blocks to be announced with headers instead of inv
+    pub send_headers: bool,
+    // last block of a truncated getblocks reply, once it is requested the peer is sent
+    // our tip so it asks for the next batch
+    pub continue_hash: Option<Vec<u8>>,
+        queue       : Vec<Vec<u8>>,
+        mean_delay  : Duration,
+        next_send   : Instant,
+}
+
+impl PeerRelay {
+    pub fn new(initiated_by_us: bool) -> Self {
+        let mean_delay = if initiated_by_us { OUTBOUND_TRICKLE } else { INBOUND_TRICKLE };
+        Self {
+            known       : KnownInventory::new(),
+            known_addrs : KnownInventory::new(),
+            requested   : KnownInventory::new(),
+            send_headers: false,
+            continue_hash: None,
+            queue       : Vec::new(),
+            mean_delay,
+            next_send   : Instant::now() + poisson_delay(mean_delay),
+        }
+    }
+
+    pub fn queue_tx(&mut self, hash: &[u8]) {
+        if !self.known.contains(hash) && !self.queue.iter().any(|h| h == hash) {
+            self.queue.push(hash.to_vec());
+        }
+    }
+
+    // transactions to announce now, in random order so their arrival order is not leaked
+    pub fn trickle(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if now < self.next_send {
+            return Vec::new()
+        }
+        self.next_send = now + poisson_delay(self.mean_delay);
+
+        let mut batch = Vec::new();
+        let mut queue = std::mem::take(&mut self.queue);
+        queue.shuffle(&mut rand::thread_rng());
+        for hash in queue {
+            if batch.len() >= MAX_ANNOUNCEMENTS {
+                self.queue.push(hash);
+            } else if self.known.insert(hash.clone()) {
+                batch.push(hash);
+            }
+        }
+        batch
+    }
+}
+
+fn poisson_delay(mean: Duration) -> Duration {
+    let uniform: f64 = rand::thread_rng().gen_range(0.0, 1.0);
+    mean.mul_f64(-(1.0 - uniform).ln())
+}
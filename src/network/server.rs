@@ -1,17 +1,29 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{ HashMap, HashSet };
+use std::net::{ IpAddr, SocketAddr };
 use std::sync::Arc;
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+use rand::seq::IteratorRandom;
 use tokio::net::{ TcpListener, TcpStream };
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 
 use blockchain::*;
-use mempool::Mempool;
+use mempool::{ Mempool, MempoolEvent, RejectReason };
 use super::message::*;
+use super::peer::{ MAX_ADDR, MAX_INV, SERVICE_FULL_HISTORY, SERVICE_NODE };
 #[cfg(feature = "rpc-server")]
 use rpc;
 
 use super::Peer;
-use super::KnownPeers;
+use super::AddrMan;
+use super::banman::{ self, BanMan };
+use super::connman::{ self, ConnectionManager };
+use super::eviction::{ self, EvictionCandidate };
+use super::ping::{ self, PingState };
+use super::Stratum;
+use super::jsonrpc::JsonRpc;
+use super::relay::{ self, PeerRelay };
+use super::sync::{ self, HeaderSync };
 
 pub struct Server {
     pub server_version  : Arc<u32>,
@@ -19,45 +31,153 @@ pub struct Server {
         sender          : mpsc::Sender<ServerMessage>,
         receiver        : mpsc::Receiver<ServerMessage>,
         mempool         : Mempool,
+        relay           : HashMap<SocketAddr, PeerRelay>,
+        sync            : HeaderSync,
+        addrman         : AddrMan,
+        connman         : ConnectionManager,
+        inbound         : HashMap<SocketAddr, EvictionCandidate>,
+        // inbound sockets which did not complete the handshake yet
+        handshaking     : HashSet<SocketAddr>,
+        max_inbound     : usize,
+        banman          : BanMan,
+        misbehavior     : HashMap<SocketAddr, u32>,
+        pings           : HashMap<SocketAddr, PingState>,
+        unknown         : UnknownCommands,
+        services        : HashMap<SocketAddr, Vec<String>>,
+        external_ip     : Option<IpAddr>,
+        port            : u16,
+        max_message_size: u64,
+        // told about every new tip
+        tip_subscribers : Vec<mpsc::Sender<()>>,
 }
 
 impl Server {
-    pub fn new() -> Server {
+    pub fn new(max_mempool: u64, max_outbound: usize, max_inbound: usize, ban_time: u64, external_ip: Option<IpAddr>, max_message_size: u64) -> Server {
         tracing::info!("Ensicoin started");
 
         let (tx, rx) = mpsc::channel(512);
+        let mut mempool = Mempool::with_max_size(max_mempool);
+        match mempool.load() {
+            Ok(count) => tracing::info!("Loaded {} mempool transactions", count),
+            Err(e) => tracing::warn!("Could not load the mempool: {:?}", e),
+        }
+        let mut events = mempool.subscribe();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    MempoolEvent::Replaced { replaced, by } => {
+                        tracing::info!("Transaction {} replaced by {}", utils::hash_to_string(&replaced), utils::hash_to_string(&by));
+                    },
+                }
+            }
+        });
         Server {
             server_version  : Arc::new(1),
             peers           : HashMap::new(),
             sender          : tx,
             receiver        : rx,
-            mempool         : Mempool::new(),
+            mempool,
+            relay           : HashMap::new(),
+            sync            : HeaderSync::new(),
+            addrman         : AddrMan::load().unwrap(),
+            connman         : ConnectionManager::new(max_outbound),
+            inbound         : HashMap::new(),
+            handshaking     : HashSet::new(),
+            max_inbound,
+            banman          : BanMan::load(ban_time).unwrap(),
+            misbehavior     : HashMap::new(),
+            pings           : HashMap::new(),
+            unknown         : UnknownCommands::new(),
+            services        : HashMap::new(),
+            external_ip,
+            port            : 4224,
+            max_message_size,
+            tip_subscribers : Vec::new(),
         }
     }
 
-    pub async fn listen(self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        let sender = self.sender.clone();
+    pub async fn listen(mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.port = port;
+        let mut sender = self.sender.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(connman::CONNECT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if sender.send(ServerMessage::ConnectPeers).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut sender = self.sender.clone();
         tokio::spawn(async move {
-                peer_routine(sender).await;
+            let mut interval = tokio::time::interval(relay::TRICKLE_TICK);
+            loop {
+                interval.tick().await;
+                if sender.send(ServerMessage::Trickle).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut sender = self.sender.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sync::SYNC_TICK);
+            loop {
+                interval.tick().await;
+                if sender.send(ServerMessage::SyncTick).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut sender = self.sender.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ping::PING_TICK);
+            loop {
+                interval.tick().await;
+                if sender.send(ServerMessage::PingTick).await.is_err() {
+                    break;
+                }
+            }
         });
 
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), port);
         let mut listener = TcpListener::bind(&addr).await.unwrap();
-        let sender = self.sender.clone();
+        let mut sender = self.sender.clone();
         tokio::spawn(async move {
             loop {
-                let (stream, _) = listener.accept().await.unwrap();
-                tracing::info!("Incoming peer: {}", stream.peer_addr().unwrap());
-                let sender2 = sender.clone();
-                tokio::spawn(async move {
-                    Peer::new(stream, sender2, false).update().await.unwrap();
-                });
+                let (stream, addr) = listener.accept().await.unwrap();
+                tracing::info!("Incoming peer: {}", addr);
+                if sender.send(ServerMessage::AcceptPeer(stream, addr)).await.is_err() {
+                    break;
+                }
             }
         });
         self.message_listener().await;
         Ok(())
     }
 
+    pub async fn stratum(&mut self, port: u16, payout: String) {
+        let stratum = Stratum::new(self.sender.clone(), model::VarStr::from_string(payout));
+        let (sender, receiver) = mpsc::channel(1);
+        self.tip_subscribers.push(sender);
+        tokio::spawn(async move {
+            if let Err(e) = stratum.listen(port, receiver).await {
+                tracing::error!("Stratum server stopped: {:?}", e);
+            }
+        });
+    }
+
+    pub async fn json_rpc(&self, port: u16, payout: String) {
+        let rpc = JsonRpc::new(self.sender.clone(), model::VarStr::from_string(payout));
+        tokio::spawn(async move {
+            if let Err(e) = rpc.listen(port).await {
+                tracing::error!("RPC server stopped: {:?}", e);
+            }
+        });
+    }
+
     pub async fn interactive(&self) {
         let mut sender = self.sender.clone();
         tokio::spawn(async move {
@@ -84,6 +204,45 @@ impl Server {
                             Err(why) => println!("Error: {:?}", why),
                         }
                     },
+                    "estimatefee\n" => {
+                        println!("Enter a confirmation target in blocks: ");
+                        std::io::stdin().read_line(&mut ip).unwrap();
+                        match ip.trim().parse::<usize>() {
+                            Ok(target) => {
+                                let (reply, mut receiver) = mpsc::channel(1);
+                                sender.send(ServerMessage::EstimateFee(reply, target)).await.unwrap();
+                                match receiver.recv().await {
+                                    Some(ServerMessage::FeeEstimate(Some(rate))) => println!("Fee rate: {} per kB", rate),
+                                    _ => println!("Not enough data to estimate fees"),
+                                }
+                            },
+                            Err(why) => println!("Error: {:?}", why),
+                        }
+                    },
+                    "listbanned\n" => {
+                        let (reply, mut receiver) = mpsc::channel(1);
+                        sender.send(ServerMessage::ListBanned(reply)).await.unwrap();
+                        if let Some(ServerMessage::BannedList(bans)) = receiver.recv().await {
+                            for (ip, until) in &bans {
+                                println!("{} banned until {}", ip, until);
+                            }
+                            println!("{} banned addresses", bans.len());
+                        }
+                    },
+                    "clearbanned\n" => {
+                        sender.send(ServerMessage::ClearBanned).await.unwrap();
+                    },
+                    "peers\n" => {
+                        let (reply, mut receiver) = mpsc::channel(1);
+                        sender.send(ServerMessage::GetPeerInfo(reply)).await.unwrap();
+                        if let Some(ServerMessage::PeerInfo(peers)) = receiver.recv().await {
+                            for (ip, inbound, last_ping, min_ping) in &peers {
+                                let direction = if *inbound { "inbound" } else { "outbound" };
+                                println!("{} {} ping: {:?} min ping: {:?}", ip, direction, last_ping, min_ping);
+                            }
+                            println!("{} peers", peers.len());
+                        }
+                    },
                     "exit\n" => {
                         sender.send(ServerMessage::CloseServer).await.unwrap();
                     },
@@ -95,48 +254,168 @@ impl Server {
         });
     }
 
+    // ctrl-c stops the server like the exit command, so the mempool is saved
+    pub async fn handle_signals(&self) {
+        let mut sender = self.sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                tracing::warn!("Could not listen for ctrl-c: {:?}", e);
+                return
+            }
+            tracing::info!("Received ctrl-c, stopping");
+            let _ = sender.send(ServerMessage::CloseServer).await;
+        });
+    }
+
     pub async fn message_listener(mut self) {
 
         loop {
             let message = self.receiver.recv().await.unwrap();
             match message {
                 ServerMessage::CreatePeer(ip) => {
-                    if !self.peers.contains_key(&ip) {
-                        let sender = self.sender.clone();
-                        let ip = ip;
-                        match TcpStream::connect(&ip).await {
-                            Ok(tcp) => {
-                                tokio::spawn(async move {
-                                let span = tracing::span!(tracing::Level::DEBUG, "Peer spawn", ip = tcp.peer_addr().unwrap().to_string().as_str());
-                                span.in_scope(|| tokio::spawn(async move {
-                                    Peer::new(tcp, sender, true).connect().await.unwrap();
-                                }));
-                            });
+                    self.create_peer(ip);
+                },
+                ServerMessage::ConnectPeers => {
+                    let peers = &self.peers;
+                    let banman = &self.banman;
+                    let candidates = self.connman.candidates(&self.addrman, |a| {
+                        peers.contains_key(a) || banman.is_banned(&a.ip())
+                    });
+                    for addr in candidates {
+                        tracing::info!("Connecting to known peer: {}", &addr);
+                        self.create_peer(addr);
+                    }
+                },
+                ServerMessage::AcceptPeer(stream, ip) => {
+                    if self.banman.is_banned(&ip.ip()) {
+                        tracing::info!("Refusing banned peer {}", ip);
+                        continue;
+                    }
+                    if self.handshaking.len() >= eviction::MAX_HANDSHAKING {
+                        tracing::info!("Too many handshakes in progress, refusing peer {}", ip);
+                        continue;
+                    }
+                    self.handshaking.insert(ip);
+                    let sender = self.sender.clone();
+                    let self_addr = self_addr(self.external_ip, self.port, &stream);
+                    let max_message_size = self.max_message_size;
+                    tokio::spawn(async move {
+                        if let Err(e) = Peer::new(stream, sender, false, self_addr, max_message_size).update().await {
+                            tracing::debug!("Peer {} stopped: {:?}", ip, e);
+                        }
+                    });
+                },
+                ServerMessage::Misbehaving(ip, howmuch, reason) => {
+                    self.misbehaving(ip, howmuch, &reason).await;
+                },
+                ServerMessage::ListBanned(mut sender) => {
+                    match self.banman.list() {
+                        Ok(bans) => {
+                            if let Err(e) = sender.send(ServerMessage::BannedList(bans)).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
                         },
-                            Err(e) => tracing::warn!("Couldn't connect to peer {}", e),
+                        Err(e) => tracing::warn!("Banned database probably dead: {:?}", e),
+                    }
+                },
+                ServerMessage::ClearBanned => {
+                    match self.banman.clear() {
+                        Ok(()) => tracing::info!("Cleared all bans"),
+                        Err(e) => tracing::warn!("Banned database probably dead: {:?}", e),
+                    }
+                },
+                ServerMessage::PingTick => {
+                    let now = Instant::now();
+                    for (ip, state) in self.pings.iter_mut() {
+                        let peer = match self.peers.get_mut(ip) {
+                            Some(peer) => peer,
+                            None => continue,
+                        };
+                        if state.timed_out(now) {
+                            tracing::info!("Peer {} timed out", ip);
+                            if let Err(e) = peer.send(ServerMessage::CloseConnection).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
+                        } else if let Some(nonce) = state.next_ping(now) {
+                            if let Err(e) = peer.send(ServerMessage::SendPing(nonce)).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
                         }
-
-                    } else {
-                        tracing::warn!("Peer already exists");
                     }
                 },
-                ServerMessage::AddPeer(sender, ip) => {
+                ServerMessage::Pong(ip, nonce) => {
+                    let latency = match self.pings.get_mut(&ip) {
+                        Some(state) => state.pong(nonce, Instant::now()),
+                        None => None,
+                    };
+                    match latency {
+                        Some(latency) => {
+                            tracing::debug!("Peer {} answered ping in {:?}", ip, latency);
+                            if let (Some(candidate), Some(state)) = (self.inbound.get_mut(&ip), self.pings.get(&ip)) {
+                                candidate.min_ping = state.min_ping;
+                            }
+                        },
+                        None => tracing::debug!("Unexpected pong from {}", ip),
+                    }
+                },
+                ServerMessage::GetPeerInfo(mut sender) => {
+                    let peers = self.peers.keys().map(|ip| {
+                        let (last_ping, min_ping) = match self.pings.get(ip) {
+                            Some(state) => (state.last_ping, state.min_ping),
+                            None => (None, None),
+                        };
+                        (*ip, self.inbound.contains_key(ip), last_ping, min_ping)
+                    }).collect();
+                    if let Err(e) = sender.send(ServerMessage::PeerInfo(peers)).await {
+                        tracing::warn!("could not send message: {:?}", e);
+                    }
+                },
+                ServerMessage::ConnectFailed(ip) => {
+                    self.connman.failed(ip);
+                },
+                ServerMessage::AddPeer(mut sender, ip, initiated_by_us, services, listen_addr) => {
+                    // inbound peers only take a slot once their handshake is done
+                    if !initiated_by_us {
+                        self.handshaking.remove(&ip);
+                        if !self.make_inbound_room(ip).await {
+                            tracing::info!("Inbound slots full, refusing peer {}", ip);
+                            if let Err(e) = sender.send(ServerMessage::CloseConnection).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
+                            continue;
+                        }
+                        self.inbound.insert(ip, EvictionCandidate::new(ip));
+                    }
                     tracing::info!("Added new peer: {}", &ip);
                     self.peers.insert(ip, sender.clone());
-                    match KnownPeers.add_peer((ip).to_string()) {
-                        Ok(_) => (),
-                        Err(e) => { tracing::warn!("Known Peers database probably dead: {:?}", e); }
+                    self.services.insert(ip, services.clone());
+                    // inbound peers connect from an ephemeral port, the address they
+                    // advertised is the one to remember
+                    if initiated_by_us {
+                        self.addrman.good(ip, services);
+                        self.connman.connected(ip);
+                    } else if let Some(addr) = listen_addr {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        self.addrman.add(addr, ip.ip(), now);
+                    }
+                    self.relay.insert(ip, PeerRelay::new(initiated_by_us));
+                    self.pings.insert(ip, PingState::new());
+                    if self.sync.syncing_peer.is_none() && serves(&self.services, &ip, SERVICE_NODE) {
+                        self.ask_headers(ip).await;
                     }
                 },
                 ServerMessage::DeletePeer(ip) => {
                     if self.peers.contains_key(&ip) {
                         self.peers.remove(&ip);
                     }
-                    match KnownPeers.del_peer((ip).to_string()) {
-                        Ok(_) => (),
-                        Err(e) => { tracing::warn!("Known Peers database probably dead: {:?}", e); }
-                    }
-
+                    self.relay.remove(&ip);
+                    self.sync.peer_disconnected(ip);
+                    self.connman.disconnected(&ip);
+                    self.inbound.remove(&ip);
+                    self.handshaking.remove(&ip);
+                    self.misbehavior.remove(&ip);
+                    self.pings.remove(&ip);
+                    self.services.remove(&ip);
                     tracing::info!("peer deleted: {}", &ip);
                 },
                 ServerMessage::ClosePeer(ip) => {
@@ -148,31 +427,49 @@ impl Server {
                     for p in self.peers.values_mut() {
                         p.send(ServerMessage::CloseConnection).await.unwrap();
                     }
+                    if let Err(e) = self.addrman.flush() {
+                        tracing::warn!("Known Peers database probably dead: {:?}", e);
+                    }
+                    match self.mempool.dump() {
+                        Ok(count) => tracing::info!("Saved {} mempool transactions", count),
+                        Err(e) => tracing::error!("Could not save the mempool: {:?}", e),
+                    }
                     tracing::info!("Ensicoin stopped");
                     return ()
                 },
-                ServerMessage::CheckTxs(mut sender, hashes) => {
+                ServerMessage::CheckTxs(mut sender, ip, hashes) => {
                     let mut inventory = Vec::new();
                     for hash in hashes {
+                        if let Some(relay) = self.relay.get_mut(&ip) {
+                            relay.known.insert(hash.to_vec());
+                        }
                         if !self.mempool.contains_tx(hash.to_vec()) {
                             inventory.push(hash.to_vec());
                         }
                     }
+                    if let Some(relay) = self.relay.get_mut(&ip) {
+                        for hash in &inventory {
+                            relay.requested.insert(hash.clone());
+                        }
+                    }
                     sender.send(ServerMessage::AskTxs(inventory)).await.unwrap();
                 },
-                ServerMessage::GetBlocks(mut sender, message) => {
+                ServerMessage::GetBlocks(mut sender, ip, message) => {
                     let mut hashs = Vec::new();
-                    for hash in &message.block_locator {
-                        if let Ok(b) = Blockchain::get_block(&hash) {
-                            let mut hash = b.hash().unwrap();
-                            while let Ok(h) = NextHash::get_next_hash(&hash) {
-                                hashs.push((h.clone(), 1));
-                                if h == message.hash_stop {
-                                    break;
+                    if let Ok(fork) = find_fork(&message.block_locator) {
+                        let mut height = fork + 1;
+                        while let Ok(h) = ChainIndex::get_hash(height) {
+                            hashs.push((h.clone(), 1));
+                            if h == message.hash_stop {
+                                break;
+                            }
+                            if hashs.len() >= sync::MAX_BLOCKS_INV {
+                                if let Some(relay) = self.relay.get_mut(&ip) {
+                                    relay.continue_hash = Some(h);
                                 }
-                                hash = h;
+                                break;
                             }
-                            break;
+                            height += 1;
                         }
                     }
                     match sender.send(ServerMessage::GetBlocksReply(hashs)).await {
@@ -180,8 +477,42 @@ impl Server {
                         Err(e) => tracing::warn!("could not send message: {:?}", e),
                     }
                 },
-                ServerMessage::AddTx(tx) => {
-                    self.mempool.add_tx(&tx).unwrap();
+                ServerMessage::AddTx(mut sender, ip, tx) => {
+                    if let (Some(relay), Ok(hash)) = (self.relay.get_mut(&ip), tx.hash()) {
+                        let solicited = relay.requested.contains(&hash);
+                        relay.known.insert(hash);
+                        if !solicited {
+                            self.misbehaving(ip, 10, "unsolicited transaction").await;
+                        }
+                    }
+                    match self.mempool.add_tx(&tx) {
+                        Ok(accepted) => {
+                            tracing::debug!("Accepted {} transactions", accepted.len());
+                            if let Some(candidate) = self.inbound.get_mut(&ip) {
+                                candidate.last_tx = Some(Instant::now());
+                            }
+                            for (addr, relay) in self.relay.iter_mut() {
+                                if *addr == ip {
+                                    continue;
+                                }
+                                for hash in &accepted {
+                                    relay.queue_tx(hash);
+                                }
+                            }
+                        },
+                        Err(RejectReason::MissingInputs(parents)) => {
+                            tracing::debug!("Orphan transaction, asking {} parents", parents.len());
+                            if let Some(relay) = self.relay.get_mut(&ip) {
+                                for hash in &parents {
+                                    relay.requested.insert(hash.clone());
+                                }
+                            }
+                            if let Err(e) = sender.send(ServerMessage::AskTxs(parents)).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
+                        },
+                        Err(reason) => tracing::debug!("Transaction rejected: {}", reason),
+                    }
                 },
                 ServerMessage::CheckBlocks(mut sender, hashs) => {
                     let mut inv;
@@ -197,26 +528,628 @@ impl Server {
                         }
                     }
                 },
-                ServerMessage::AddBlock(block) => {
-                    Blockchain::insert_block(block.hash().unwrap(), &block).unwrap();
+                ServerMessage::AddBlock(ip, block) => {
+                    if let (Some(relay), Ok(hash)) = (self.relay.get_mut(&ip), block.hash()) {
+                        relay.known.insert(hash);
+                    }
+                    let ready = match self.sync.block_received(block) {
+                        Ok(ready) => ready,
+                        Err(e) => {
+                            tracing::error!("Something went wrong: {:?}", e);
+                            continue;
+                        },
+                    };
+                    let mut last = None;
+                    for block in ready {
+                        let hash = block.hash().unwrap();
+                        match self.connect_block(&block) {
+                            Ok(false) => self.sync.block_stored(&hash),
+                            Ok(true) => {
+                                self.sync.block_connected(&hash, block.height);
+                                if let Some(candidate) = self.inbound.get_mut(&ip) {
+                                    candidate.last_block = Some(Instant::now());
+                                }
+                                last = Some(block);
+                            },
+                            // we are missing blocks, not the peer's fault
+                            Err(utils::Error::UnknownParent) => {
+                                tracing::debug!("Block {} has an unknown parent", utils::hash_to_string(&hash));
+                                if self.sync.syncing_peer.is_none() {
+                                    self.ask_headers(ip).await;
+                                }
+                                break;
+                            },
+                            Err(e) => {
+                                tracing::warn!("Could not connect block: {:?}", e);
+                                self.sync.block_failed(&hash);
+                                if let utils::Error::BlockNotValid = e {
+                                    self.misbehaving(ip, 100, "invalid block").await;
+                                }
+                                break;
+                            },
+                        }
+                    }
+                    // blocks are only announced once the node caught up
+                    if let Some(block) = last {
+                        if self.sync.is_synced() {
+                            self.announce_block(&block).await;
+                        }
+                    }
+                    self.request_blocks().await;
+                },
+                ServerMessage::AddHeaders(mut sender, ip, headers) => {
+                    let full = headers.len() >= sync::MAX_HEADERS;
+                    match self.sync.add_headers(headers) {
+                        Ok(count) => {
+                            tracing::debug!("Accepted {} new headers", count);
+                            if full {
+                                match self.sync.locator() {
+                                    Ok(locator) => {
+                                        if let Err(e) = sender.send(ServerMessage::AskHeaders(locator)).await {
+                                            tracing::warn!("could not send message: {:?}", e);
+                                        }
+                                    },
+                                    Err(e) => tracing::error!("Something went wrong: {:?}", e),
+                                }
+                            } else if self.sync.syncing_peer == Some(ip) {
+                                tracing::info!("Headers synchronised with {}", ip);
+                                self.sync.syncing_peer = None;
+                            }
+                        },
+                        Err(e) => {
+                            tracing::warn!("Invalid headers from {}: {:?}", ip, e);
+                            if let utils::Error::BlockNotValid = e {
+                                self.misbehaving(ip, 100, "invalid headers").await;
+                            }
+                            if self.sync.syncing_peer == Some(ip) {
+                                self.sync.syncing_peer = None;
+                            }
+                        },
+                    }
+                    self.request_blocks().await;
+                },
+                ServerMessage::GetHeaders(mut sender, message) => {
+                    let mut headers = Vec::new();
+                    if let Ok(fork) = find_fork(&message.block_locator) {
+                        let mut height = fork + 1;
+                        while let Ok(h) = ChainIndex::get_hash(height) {
+                            match Blockchain::get_block(&h) {
+                                Ok(block) => headers.push(block),
+                                Err(_) => break,
+                            }
+                            if h == message.hash_stop || headers.len() >= sync::MAX_HEADERS {
+                                break;
+                            }
+                            height += 1;
+                        }
+                    }
+                    if let Err(e) = sender.send(ServerMessage::GetHeadersReply(headers)).await {
+                        tracing::warn!("could not send message: {:?}", e);
+                    }
+                },
+                ServerMessage::SyncTick => {
+                    for ip in self.sync.stalled(Instant::now()) {
+                        tracing::warn!("Peer {} stalled the block download", ip);
+                    }
+                    self.request_blocks().await;
+                },
+                ServerMessage::MinedBlock(block) => {
+                    match self.connect_block(&block) {
+                        Ok(true) => {
+                            if let Ok(hash) = block.hash() {
+                                self.sync.block_connected(&hash, block.height);
+                            }
+                            self.announce_block(&block).await;
+                        },
+                        Ok(false) => tracing::info!("Mined block is not on the main chain"),
+                        Err(e) => tracing::warn!("Could not connect mined block: {:?}", e),
+                    }
+                },
+                ServerMessage::SendHeaders(ip) => {
+                    if let Some(relay) = self.relay.get_mut(&ip) {
+                        relay.send_headers = true;
+                    }
+                },
+                ServerMessage::Trickle => {
+                    let now = Instant::now();
+                    for (ip, relay) in self.relay.iter_mut() {
+                        let batch = relay.trickle(now);
+                        if batch.is_empty() {
+                            continue;
+                        }
+                        if let Some(peer) = self.peers.get_mut(ip) {
+                            let inventory = batch.into_iter().map(|h| (h, 0)).collect();
+                            if let Err(e) = peer.send(ServerMessage::Announce(inventory)).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
+                        }
+                    }
+                },
+                ServerMessage::GetData(mut sender, ip, items) => {
+                    let mut not_found = Vec::new();
+                    for (hash, hash_type) in items {
+                        let message = match hash_type {
+                            0 => self.mempool.txs.get(&hash).map(|e| ServerMessage::SendTx(e.tx.clone())),
+                            _ => Blockchain::get_block(&hash).ok().map(ServerMessage::SendBlock),
+                        };
+                        match message {
+                            Some(message) => {
+                                if let Err(e) = sender.send(message).await {
+                                    tracing::warn!("could not send message: {:?}", e);
+                                }
+                            },
+                            None => not_found.push((hash.clone(), hash_type)),
+                        }
+
+                        let relay = match self.relay.get_mut(&ip) {
+                            Some(relay) => relay,
+                            None => continue,
+                        };
+                        if relay.continue_hash.as_ref() == Some(&hash) {
+                            relay.continue_hash = None;
+                            if let Ok((tip, _)) = Blockchain::get_tip() {
+                                if let Err(e) = sender.send(ServerMessage::Announce(vec![(tip, 1)])).await {
+                                    tracing::warn!("could not send message: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    if !not_found.is_empty() {
+                        if let Err(e) = sender.send(ServerMessage::SendNotFound(not_found)).await {
+                            tracing::warn!("could not send message: {:?}", e);
+                        }
+                    }
+                },
+                ServerMessage::NotFound(ip, items) => {
+                    for (hash, hash_type) in items {
+                        if hash_type != 0 {
+                            self.sync.not_found(ip, &hash);
+                        }
+                    }
+                    self.request_blocks().await;
+                },
+                ServerMessage::GetMempool(mut sender, ip) => {
+                    let mut inventory = Vec::new();
+                    for hash in self.mempool.txs.keys().take(MAX_INV) {
+                        if let Some(relay) = self.relay.get_mut(&ip) {
+                            relay.known.insert(hash.clone());
+                        }
+                        inventory.push((hash.clone(), 0));
+                    }
+                    if let Err(e) = sender.send(ServerMessage::Announce(inventory)).await {
+                        tracing::warn!("could not send message: {:?}", e);
+                    }
+                },
+                ServerMessage::UnknownMessage(ip, name) => {
+                    match self.unknown.record(&name) {
+                        Some(1) => tracing::warn!("Peer {} sent unknown command {:?}", ip, name),
+                        Some(count) => tracing::debug!("Peer {} sent unknown command {:?}, received {} times", ip, name, count),
+                        None => tracing::debug!("Peer {} sent unknown command {:?}", ip, name),
+                    }
+                },
+                ServerMessage::GetAddr(mut sender) => {
+                    let addresses = self.addrman.sample(MAX_ADDR).iter()
+                        .map(|i| model::Address::from_socket_addr(&i.addr, i.last_seen))
+                        .collect();
+                    if let Err(e) = sender.send(ServerMessage::SendAddrs(addresses)).await {
+                        tracing::warn!("could not send message: {:?}", e);
+                    }
+                },
+                ServerMessage::AddAddrs(ip, addresses) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    let relay = addresses.len() <= relay::MAX_ADDR_RELAY;
+                    for address in addresses {
+                        let addr = address.socket_addr();
+                        if addr.port() == 0 || addr.ip().is_unspecified() {
+                            continue;
+                        }
+                        let key = addr.to_string().into_bytes();
+                        if let Some(source) = self.relay.get_mut(&ip) {
+                            source.known_addrs.insert(key.clone());
+                        }
+                        self.addrman.add(addr, ip.ip(), address.timestamp());
+
+                        if !relay || address.timestamp() + relay::ADDR_FRESHNESS < now {
+                            continue;
+                        }
+                        let targets = self.relay.keys()
+                            .filter(|p| **p != ip)
+                            .cloned()
+                            .choose_multiple(&mut rand::thread_rng(), relay::ADDR_RELAY_PEERS);
+                        for target in targets {
+                            if !self.relay.get_mut(&target).unwrap().known_addrs.insert(key.clone()) {
+                                continue;
+                            }
+                            if let Some(peer) = self.peers.get_mut(&target) {
+                                if let Err(e) = peer.send(ServerMessage::SendAddrs(vec![address.clone()])).await {
+                                    tracing::warn!("could not send message: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                },
+                ServerMessage::EstimateFee(mut sender, target) => {
+                    let estimate = self.mempool.fees.estimate(target);
+                    if let Err(e) = sender.send(ServerMessage::FeeEstimate(estimate)).await {
+                        tracing::warn!("could not send message: {:?}", e);
+                    }
+                },
+                ServerMessage::GetBlockTemplate(mut sender, coinbase_script) => {
+                    let template = Blockchain::get_tip().and_then(|(hash, tip)| {
+                        self.mempool.block_template(hash, &tip, coinbase_script, block::MAX_BLOCK_SIZE)
+                    });
+                    match template {
+                        Ok(template) => {
+                            if let Err(e) = sender.send(ServerMessage::BlockTemplate(template)).await {
+                                tracing::warn!("could not send message: {:?}", e);
+                            }
+                        },
+                        Err(e) => tracing::error!("Could not build block template: {:?}", e),
+                    }
                 },
                 _ => ()
             }
         }
     }
 
+    // the transactions of a block are checked against the utxos when it gets on the main
+    // chain, side blocks only have their header and merkle root checked; returns whether
+    // the tip changed
+    fn connect_block(&mut self, block: &Block) -> Result<bool, utils::Error> {
+        if !Blockchain::has_block(&block.previous_hash)? {
+            return Err(utils::Error::UnknownParent)
+        }
+        let hash = block.hash()?;
+        if Blockchain::has_block(&hash)? {
+            return Ok(false)
+        }
+        let previous = Blockchain::get_block(&block.previous_hash)?;
+        if !block.check_header(&previous)? || !block.is_sane() {
+            return Err(utils::Error::BlockNotValid)
+        }
+
+        let (tip_height, tip) = ChainIndex::tip()?;
+        if tip == block.previous_hash {
+            if !block.is_valid()? {
+                return Err(utils::Error::BlockNotValid)
+            }
+            Blockchain::insert_block(hash.clone(), block)?;
+            self.connect_tip(hash, block)?;
+        } else {
+            Blockchain::insert_block(hash.clone(), block)?;
+            // difficulty is constant, the heaviest chain is the longest one
+            if block.height <= tip_height {
+                return Ok(false)
+            }
+            self.reorganize(hash, block)?;
+        }
+        // the estimates only change with blocks, don't lose them on a crash
+        if let Err(e) = self.mempool.fees.save() {
+            tracing::warn!("Could not save fee estimates: {:?}", e);
+        }
+        // a full channel already has a notification pending
+        self.tip_subscribers.retain_mut(|s| !matches!(s.try_send(()), Err(TrySendError::Closed(_))));
+        Ok(true)
+    }
+
+    // the branch ending with block became longer than the main chain: disconnects the main
+    // chain down to the fork and connects the branch, going back to the main chain if a
+    // block of the branch is invalid
+    fn reorganize(&mut self, hash: Vec<u8>, block: &Block) -> Result<(), utils::Error> {
+        let mut branch = vec![(hash, block.clone())];
+        let mut fork = block.previous_hash.clone();
+        loop {
+            let parent = Blockchain::get_block(&fork)?;
+            if ChainIndex::is_main_chain(&fork, parent.height)? {
+                break;
+            }
+            let previous = parent.previous_hash.clone();
+            branch.push((fork, parent));
+            fork = previous;
+        }
+        branch.reverse();
+        tracing::info!("Reorganisation: {} blocks replace the main chain above {}", branch.len(), utils::hash_to_string(&fork));
+
+        let mut disconnected = Vec::new();
+        while ChainIndex::tip()?.1 != fork {
+            disconnected.push(self.disconnect_tip()?);
+        }
+        for (connected, (hash, block)) in branch.iter().enumerate() {
+            if block.is_valid()? {
+                self.connect_tip(hash.clone(), block)?;
+                continue;
+            }
+            for _ in 0..connected {
+                self.disconnect_tip()?;
+            }
+            for block in disconnected.iter().rev() {
+                self.connect_tip(block.hash()?, block)?;
+            }
+            for (hash, _) in &branch[connected..] {
+                Blockchain::remove_block(hash)?;
+            }
+            return Err(utils::Error::BlockNotValid)
+        }
+        Ok(())
+    }
+
+    fn connect_tip(&mut self, hash: Vec<u8>, block: &Block) -> Result<(), utils::Error> {
+        Blockchain::connect_tip(hash, block)?;
+        Utxos::connect_block(block)?;
+        let conflicts = self.mempool.connect_block(block)?;
+        if !conflicts.is_empty() {
+            tracing::debug!("Removed {} transactions conflicting with the block", conflicts.len());
+        }
+        Ok(())
+    }
+
+    fn disconnect_tip(&mut self) -> Result<Block, utils::Error> {
+        let block = Blockchain::disconnect_tip()?;
+        Utxos::disconnect_block(&block)?;
+        let readded = self.mempool.disconnect_block(&block)?;
+        if !readded.is_empty() {
+            tracing::debug!("Put {} transactions of a disconnected block back in the mempool", readded.len());
+        }
+        Ok(block)
+    }
+
+    // evicts an inbound peer if every slot is taken, false when none can be evicted
+    async fn make_inbound_room(&mut self, ip: SocketAddr) -> bool {
+        if self.inbound.len() < self.max_inbound {
+            return true
+        }
+        let candidates = self.inbound.values().cloned().collect();
+        let evicted = match eviction::select_to_evict(candidates) {
+            Some(evicted) => evicted,
+            None => return false,
+        };
+        tracing::info!("Evicting peer {} for {}", evicted, ip);
+        self.inbound.remove(&evicted);
+        if let Some(peer) = self.peers.get_mut(&evicted) {
+            if let Err(e) = peer.send(ServerMessage::CloseConnection).await {
+                tracing::warn!("could not send message: {:?}", e);
+            }
+        }
+        true
+    }
+
+    // bumps the misbehavior score of a peer, past the threshold its ip is banned and
+    // every connection from it is closed
+    async fn misbehaving(&mut self, ip: SocketAddr, howmuch: u32, reason: &str) {
+        let score = self.misbehavior.entry(ip).or_insert(0);
+        *score += howmuch;
+        tracing::warn!("Peer {} misbehaving (score {}): {}", ip, score, reason);
+        if *score < banman::BAN_THRESHOLD {
+            return
+        }
+
+        match self.banman.ban(ip.ip()) {
+            Ok(()) => tracing::info!("Banned {}", ip.ip()),
+            Err(e) => tracing::warn!("Banned database probably dead: {:?}", e),
+        }
+        for (addr, peer) in self.peers.iter_mut() {
+            if addr.ip() == ip.ip() {
+                if let Err(e) = peer.send(ServerMessage::CloseConnection).await {
+                    tracing::warn!("could not send message: {:?}", e);
+                }
+            }
+        }
+    }
+
+    // connects in the background, the server is told with ConnectFailed when it fails
+    fn create_peer(&mut self, ip: SocketAddr) {
+        if self.peers.contains_key(&ip) {
+            tracing::warn!("Peer already exists");
+            return
+        }
+        self.addrman.attempt(&ip);
+        let mut sender = self.sender.clone();
+        let (external_ip, port, max_message_size) = (self.external_ip, self.port, self.max_message_size);
+        tokio::spawn(async move {
+            match tokio::time::timeout(connman::CONNECT_TIMEOUT, TcpStream::connect(&ip)).await {
+                Ok(Ok(tcp)) => {
+                    let self_addr = self_addr(external_ip, port, &tcp);
+                    let span = tracing::span!(tracing::Level::DEBUG, "Peer spawn", ip = ip.to_string().as_str());
+                    span.in_scope(|| tokio::spawn(async move {
+                        if let Err(e) = Peer::new(tcp, sender, true, self_addr, max_message_size).connect().await {
+                            tracing::debug!("Peer {} stopped: {:?}", ip, e);
+                        }
+                    }));
+                },
+                Ok(Err(e)) => {
+                    tracing::warn!("Couldn't connect to peer {}", e);
+                    let _ = sender.send(ServerMessage::ConnectFailed(ip)).await;
+                },
+                Err(_) => {
+                    tracing::warn!("Connection to peer {} timed out", ip);
+                    let _ = sender.send(ServerMessage::ConnectFailed(ip)).await;
+                },
+            }
+        });
+    }
+
+    async fn ask_headers(&mut self, ip: SocketAddr) {
+        let locator = match self.sync.locator() {
+            Ok(locator) => locator,
+            Err(e) => {
+                tracing::error!("Something went wrong: {:?}", e);
+                return
+            },
+        };
+        if let Some(peer) = self.peers.get_mut(&ip) {
+            self.sync.syncing_peer = Some(ip);
+            if let Err(e) = peer.send(ServerMessage::AskHeaders(locator)).await {
+                tracing::warn!("could not send message: {:?}", e);
+            }
+        }
+    }
+
+    // spreads the missing block bodies over the peers keeping every block
+    async fn request_blocks(&mut self) {
+        let services = &self.services;
+        for (ip, peer) in self.peers.iter_mut() {
+            if !serves(services, ip, SERVICE_FULL_HISTORY) {
+                continue;
+            }
+            let hashs = match self.sync.next_downloads(*ip) {
+                Ok(hashs) => hashs,
+                Err(e) => {
+                    tracing::error!("Something went wrong: {:?}", e);
+                    return
+                },
+            };
+            if hashs.is_empty() {
+                continue;
+            }
+            let inventory = hashs.into_iter().map(|h| (h, 1)).collect();
+            if let Err(e) = peer.send(ServerMessage::AskBlocks(inventory)).await {
+                tracing::warn!("could not send message: {:?}", e);
+            }
+        }
+    }
+
+    // tells peers about a block which became the tip, skipping the ones already knowing it
+    async fn announce_block(&mut self, block: &Block) {
+        let hash = match Blockchain::get_tip() {
+            Ok((hash, _)) if block.hash().ok() == Some(hash.clone()) => hash,
+            _ => return,
+        };
+        for (ip, relay) in self.relay.iter_mut() {
+            if !relay.known.insert(hash.clone()) {
+                continue;
+            }
+            let message = if relay.send_headers {
+                ServerMessage::AnnounceHeaders(vec![block.clone()])
+            } else {
+                ServerMessage::Announce(vec![(hash.clone(), 1)])
+            };
+            if let Some(peer) = self.peers.get_mut(ip) {
+                if let Err(e) = peer.send(message).await {
+                    tracing::warn!("could not send message: {:?}", e);
+                }
+            }
+        }
+    }
 }
 
-async fn peer_routine(mut sender: tokio::sync::mpsc::Sender<ServerMessage>) {
-    let db = KnownPeers;
-    let span = tracing::span!(tracing::Level::DEBUG, "known peer routine");
-    let _ = span.enter();
-    loop {
-        let peers = db.get_peers().unwrap();
-        for p in peers {
-            tracing::info!("Connecting to known peer: {}", &p);
-            sender.send(ServerMessage::CreatePeer(p.parse().unwrap())).await.unwrap();
-        }
-        std::thread::sleep(std::time::Duration::from_secs(180));
+// height of the first locator entry on our main chain, the last block the peer has in
+// common with us
+fn find_fork(locator: &[Vec<u8>]) -> Result<u32, utils::Error> {
+    for hash in locator {
+        if let Ok(block) = Blockchain::get_block(hash) {
+            if ChainIndex::is_main_chain(hash, block.height)? {
+                return Ok(block.height)
+            }
+        }
+    }
+    Ok(0)
+}
+
+// the peer advertised the service during the handshake
+fn serves(services: &HashMap<SocketAddr, Vec<String>>, ip: &SocketAddr, service: &str) -> bool {
+    services.get(ip).is_some_and(|s| s.iter().any(|s| s == service))
+}
+
+// address advertised to a peer on this connection
+fn self_addr(external_ip: Option<IpAddr>, port: u16, stream: &TcpStream) -> SocketAddr {
+    let ip = match (external_ip, stream.local_addr()) {
+        (Some(ip), _) => ip,
+        (None, Ok(local)) => local.ip(),
+        (None, Err(_)) => IpAddr::from([0, 0, 0, 0]),
+    };
+    SocketAddr::new(ip, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block::BLOCK_REWARD;
+    use blockchain::transaction::{ Outpoint, Transaction, TxIn, TxOut };
+    use model::{ VarStr, VarUint };
+    use utils::datadir::TempDataDir;
+    use utils::merkle_tree::compute_merkle_root;
+
+    fn child(parent: &Block, value: u64, txs: Vec<Transaction>) -> (Vec<u8>, Block) {
+        let mut block = parent.clone();
+        block.previous_hash = parent.hash().unwrap();
+        block.height = parent.height + 1;
+        block.transactions = vec![Transaction::coinbase(block.height, value, VarStr::from_string(String::new()))];
+        block.transactions.extend(txs);
+        block.merkle_root = compute_merkle_root(block.transactions.iter().map(|tx| tx.hash().unwrap()).collect());
+        let hash = block.hash().unwrap();
+        Blockchain::insert_block(hash.clone(), &block).unwrap();
+        (hash, block)
+    }
+
+    fn confirmed(block: &Block) -> bool {
+        Utxos::tx_exist(block.transactions[0].hash().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn longer_branch_replaces_main_chain() {
+        let _data_dir = TempDataDir::new("server-reorganize");
+        Blockchain::add_genesis_block().unwrap();
+        let mut server = Server::new(1_000_000, 0, 0, 0, None, 1_000_000);
+        let genesis = Block::genesis_block().unwrap();
+
+        let (main_hash, main) = child(&genesis, 1, Vec::new());
+        server.connect_tip(main_hash, &main).unwrap();
+        let (_, side1) = child(&genesis, 2, Vec::new());
+        let (side2_hash, side2) = child(&side1, 3, Vec::new());
+        server.reorganize(side2_hash.clone(), &side2).unwrap();
+        assert_eq!(ChainIndex::tip().unwrap(), (2, side2_hash.clone()));
+        assert!(!confirmed(&main) && confirmed(&side1) && confirmed(&side2));
+
+        // the invalid block and its descendants are dropped, the main chain is restored
+        let (_, main2) = child(&main, 4, Vec::new());
+        let (invalid_hash, invalid) = child(&main2, BLOCK_REWARD + 1, Vec::new());
+        let (last_hash, last) = child(&invalid, 5, Vec::new());
+        assert!(server.reorganize(last_hash.clone(), &last).is_err());
+        assert_eq!(ChainIndex::tip().unwrap(), (2, side2_hash));
+        assert!(!confirmed(&main) && !confirmed(&main2) && confirmed(&side1) && confirmed(&side2));
+        assert!(Blockchain::has_block(&main2.hash().unwrap()).unwrap());
+        assert!(!Blockchain::has_block(&invalid_hash).unwrap());
+        assert!(!Blockchain::has_block(&last_hash).unwrap());
+    }
+
+    #[tokio::test]
+    async fn disconnected_transactions_back_in_mempool() {
+        let _data_dir = TempDataDir::new("server-disconnect");
+        Blockchain::add_genesis_block().unwrap();
+        let mut server = Server::new(1_000_000, 0, 0, 0, None, 1_000_000);
+        let genesis = Block::genesis_block().unwrap();
+
+        let (main1_hash, main1) = child(&genesis, 100, Vec::new());
+        server.connect_tip(main1_hash, &main1).unwrap();
+        let spend = Transaction {
+            version: 0,
+            flags_count: VarUint::from_u64(0),
+            flags: Vec::new(),
+            inputs_count: VarUint::from_u64(1),
+            inputs: vec![TxIn {
+                previous_output: Outpoint { hash: main1.transactions[0].hash().unwrap(), index: 0 },
+                script: VarStr::from_string(String::new()),
+                shash: Vec::new(),
+            }],
+            outputs_count: VarUint::from_u64(1),
+            outputs: vec![TxOut { value: 90, script: VarStr::from_string(String::new()) }],
+        };
+        let spend_hash = spend.hash().unwrap();
+        let (main2_hash, main2) = child(&main1, 10, vec![spend]);
+        server.connect_tip(main2_hash, &main2).unwrap();
+        assert!(!server.mempool.txs.contains_key(&spend_hash));
+
+        let (_, side2) = child(&main1, 20, Vec::new());
+        let (side3_hash, side3) = child(&side2, 30, Vec::new());
+        server.reorganize(side3_hash, &side3).unwrap();
+        assert!(server.mempool.txs.contains_key(&spend_hash));
+
+        // once its input is gone too, the transaction leaves the mempool
+        let (_, other1) = child(&genesis, 40, Vec::new());
+        let (_, other2) = child(&other1, 50, Vec::new());
+        let (_, other3) = child(&other2, 60, Vec::new());
+        let (other4_hash, other4) = child(&other3, 70, Vec::new());
+        server.reorganize(other4_hash, &other4).unwrap();
+        assert!(!server.mempool.txs.contains_key(&spend_hash));
     }
 }
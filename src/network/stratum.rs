@@ -0,0 +1,395 @@
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use serde_json::{ json, Value };
+use tokio::io::{ AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader };
+use tokio::net::TcpListener;
+use tokio::sync::{ mpsc, Mutex };
+use tracing::{ debug, info, warn };
+
+use mempool::BlockTemplate;
+use model::VarStr;
+use utils::Error;
+use super::message::ServerMessage;
+
+const JOB_REFRESH       : Duration  = Duration::from_secs(30);
+const MAX_JOBS          : usize     = 8;
+const RETARGET_SHARES   : u64       = 16;
+const SECONDS_PER_SHARE : u64       = 10;
+
+// target of a difficulty 1 share
+const MAX_TARGET: [u8; 32] = [0,0,0,0,0xFF,0xFF,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0];
+
+pub fn target_from_difficulty(difficulty: u64) -> Vec<u8> {
+    let divisor = u128::from(difficulty.max(1));
+    let mut remainder: u128 = 0;
+    let mut target = Vec::with_capacity(32);
+    for byte in MAX_TARGET.iter() {
+        let current = (remainder << 8) | u128::from(*byte);
+        target.push((current / divisor) as u8);
+        remainder = current % divisor;
+    }
+    target
+}
+
+struct Job {
+    id          : String,
+    template    : BlockTemplate,
+}
+
+struct Jobs {
+    counter     : u64,
+    jobs        : VecDeque<Job>,
+    workers     : HashMap<u64, mpsc::Sender<String>>,
+    next_worker : u64,
+}
+
+impl Jobs {
+    fn get(&self, id: &str) -> Option<&BlockTemplate> {
+        self.jobs.iter().find(|j| j.id == id).map(|j| &j.template)
+    }
+
+    fn notify(&self, clean: bool) -> Option<String> {
+        self.jobs.back().map(|job| {
+            let mut header = job.template.block(0).send_header().unwrap();
+            header.truncate(header.len() - 8);
+            json!({
+                "id": Value::Null,
+                "method": "mining.notify",
+                "params": [
+                    job.id,
+                    utils::hash_to_string(&header),
+                    utils::hash_to_string(&job.template.difficulty),
+                    clean,
+                ],
+            }).to_string()
+        })
+    }
+}
+
+struct Worker {
+    id              : u64,
+    name            : Option<String>,
+    difficulty      : u64,
+    shares          : u64,
+    last_retarget   : Instant,
+    submitted       : HashSet<(String, u64)>,
+}
+
+// stratum-like mining server, one json request per line
+#[derive(Clone)]
+pub struct Stratum {
+    server_sender   : mpsc::Sender<ServerMessage>,
+    payout          : VarStr,
+    jobs            : Arc<Mutex<Jobs>>,
+}
+
+impl Stratum {
+    pub fn new(server_sender: mpsc::Sender<ServerMessage>, payout: VarStr) -> Stratum {
+        Stratum {
+            server_sender,
+            payout,
+            jobs: Arc::new(Mutex::new(Jobs {
+                counter: 0,
+                jobs: VecDeque::new(),
+                workers: HashMap::new(),
+                next_worker: 0,
+            })),
+        }
+    }
+
+    // tip_changed gets a message each time a block is connected
+    pub async fn listen(self, port: u16, mut tip_changed: mpsc::Receiver<()>) -> Result<(), Error> {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), port);
+        let mut listener = TcpListener::bind(&addr).await?;
+        info!("Stratum server listening on port {}", port);
+
+        let refresher = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(JOB_REFRESH);
+            loop {
+                interval.tick().await;
+                if let Err(e) = refresher.refresh().await {
+                    warn!("Could not refresh mining jobs: {:?}", e);
+                }
+            }
+        });
+        let refresher = self.clone();
+        tokio::spawn(async move {
+            while tip_changed.recv().await.is_some() {
+                if let Err(e) = refresher.refresh().await {
+                    warn!("Could not refresh mining jobs: {:?}", e);
+                }
+            }
+        });
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("Incoming worker: {}", addr);
+            let stratum = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = stratum.handle_worker(stream).await {
+                    debug!("Worker {} disconnected: {:?}", addr, e);
+                }
+            });
+        }
+    }
+
+    // asks the server for a new template and pushes it to every worker
+    pub async fn refresh(&self) -> Result<(), Error> {
+        let (sender, mut receiver) = mpsc::channel(1);
+        self.server_sender.clone().send(ServerMessage::GetBlockTemplate(sender, self.payout.clone())).await?;
+        let template = match receiver.recv().await {
+            Some(ServerMessage::BlockTemplate(template)) => template,
+            _ => return Err(Error::ConnectionClosed),
+        };
+
+        let mut jobs = self.jobs.lock().await;
+        let clean = match jobs.jobs.back() {
+            Some(job) => job.template.previous_hash != template.previous_hash,
+            None => true,
+        };
+        if clean {
+            jobs.jobs.clear();
+        }
+        jobs.counter += 1;
+        let id = format!("{:x}", jobs.counter);
+        jobs.jobs.push_back(Job { id, template });
+        if jobs.jobs.len() > MAX_JOBS {
+            jobs.jobs.pop_front();
+        }
+
+        // never wait on a worker with the lock held, a worker that can't keep up is dropped
+        let notify = jobs.notify(clean).unwrap();
+        jobs.workers.retain(|_, sender| sender.try_send(notify.clone()).is_ok());
+        Ok(())
+    }
+
+    pub async fn handle_worker<S>(self, stream: S) -> Result<(), Error>
+        where S: AsyncRead + AsyncWrite + Send + 'static {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let (sender, mut receiver) = mpsc::channel::<String>(64);
+        tokio::spawn(async move {
+            while let Some(mut line) = receiver.recv().await {
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut worker = {
+            let mut jobs = self.jobs.lock().await;
+            jobs.next_worker += 1;
+            Worker {
+                id: jobs.next_worker,
+                name: None,
+                difficulty: 1,
+                shares: 0,
+                last_retarget: Instant::now(),
+                submitted: HashSet::new(),
+            }
+        };
+
+        let mut lines = BufReader::new(reader).lines();
+        let result = self.worker_loop(&mut worker, &mut lines, sender).await;
+        self.jobs.lock().await.workers.remove(&worker.id);
+        result
+    }
+
+    async fn worker_loop<R>(&self, worker: &mut Worker, lines: &mut tokio::io::Lines<BufReader<R>>, mut sender: mpsc::Sender<String>) -> Result<(), Error>
+        where R: AsyncRead + Unpin {
+        while let Some(line) = lines.next_line().await? {
+            let request: Value = match serde_json::from_str(&line) {
+                Ok(r) => r,
+                Err(_) => return Err(Error::ParseError("Invalid stratum request".to_string())),
+            };
+            let id = request["id"].clone();
+            let params = request["params"].as_array().cloned().unwrap_or_default();
+
+            match request["method"].as_str() {
+                Some("mining.subscribe") => {
+                    sender.send(reply(id, json!([format!("{:x}", worker.id)]))).await?;
+                    sender.send(set_difficulty(worker.difficulty)).await?;
+                    let notify = {
+                        let mut jobs = self.jobs.lock().await;
+                        jobs.workers.insert(worker.id, sender.clone());
+                        jobs.notify(true)
+                    };
+                    if let Some(notify) = notify {
+                        sender.send(notify).await?;
+                    }
+                },
+                Some("mining.authorize") => {
+                    worker.name = params.first().and_then(|v| v.as_str()).map(|s| s.to_string());
+                    sender.send(reply(id, json!(worker.name.is_some()))).await?;
+                },
+                Some("mining.submit") => {
+                    let response = match self.submit(worker, &params).await {
+                        Ok(()) => reply(id, json!(true)),
+                        Err((code, message)) => error(id, code, message),
+                    };
+                    sender.send(response).await?;
+                    if worker.shares >= RETARGET_SHARES {
+                        self.retarget(worker);
+                        sender.send(set_difficulty(worker.difficulty)).await?;
+                    }
+                },
+                _ => sender.send(error(id, 20, "Unknown method")).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn submit(&self, worker: &mut Worker, params: &[Value]) -> Result<(), (u32, &'static str)> {
+        if worker.name.is_none() {
+            return Err((24, "Unauthorized worker"))
+        }
+        let job_id = params.get(1).and_then(|v| v.as_str()).ok_or((20, "Missing job id"))?;
+        let nonce = params.get(2).and_then(|v| v.as_str())
+            .and_then(|n| u64::from_str_radix(n, 16).ok())
+            .ok_or((20, "Invalid nonce"))?;
+
+        let block = {
+            let jobs = self.jobs.lock().await;
+            worker.submitted.retain(|(id, _)| jobs.get(id).is_some());
+            match jobs.get(job_id) {
+                Some(template) => template.block(nonce),
+                None => return Err((21, "Job not found")),
+            }
+        };
+        if !worker.submitted.insert((job_id.to_string(), nonce)) {
+            return Err((22, "Duplicate share"))
+        }
+
+        // a block is always a valid share, even when the block target is the easier one
+        let hash = block.hash_header().map_err(|_| (20, "Invalid block"))?;
+        let solved = utils::hash_meets_target(&hash, &block.difficulty);
+        if !solved && !utils::hash_meets_target(&hash, &target_from_difficulty(worker.difficulty)) {
+            return Err((23, "Low difficulty share"))
+        }
+        worker.shares += 1;
+
+        // the jobs are refreshed once the server connected the block
+        if solved {
+            info!("Worker {:?} found block {}", worker.name, utils::hash_to_string(&hash));
+            if self.server_sender.clone().send(ServerMessage::MinedBlock(block)).await.is_err() {
+                return Err((20, "Node unavailable"))
+            }
+        }
+        Ok(())
+    }
+
+    // aims at one share every SECONDS_PER_SHARE seconds for each worker
+    fn retarget(&self, worker: &mut Worker) {
+        let elapsed = worker.last_retarget.elapsed().as_secs().max(1);
+        let expected = worker.shares * SECONDS_PER_SHARE;
+        let difficulty = u128::from(worker.difficulty) * u128::from(expected) / u128::from(elapsed);
+        worker.difficulty = difficulty.max(1).min(u128::from(u64::MAX)) as u64;
+        worker.shares = 0;
+        worker.last_retarget = Instant::now();
+    }
+}
+
+fn reply(id: Value, result: Value) -> String {
+    json!({ "id": id, "result": result, "error": Value::Null }).to_string()
+}
+
+fn error(id: Value, code: u32, message: &str) -> String {
+    json!({ "id": id, "result": Value::Null, "error": [code, message, Value::Null] }).to_string()
+}
+
+fn set_difficulty(difficulty: u64) -> String {
+    json!({ "id": Value::Null, "method": "mining.set_difficulty", "params": [difficulty] }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    fn template() -> BlockTemplate {
+        BlockTemplate {
+            version: 0,
+            previous_hash: vec![0; 32],
+            height: 1,
+            timestamp: 0,
+            difficulty: vec![0; 32],
+            transactions: Vec::new(),
+            merkle_root: vec![0; 32],
+            fees: 0,
+            coinbase_value: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_authorize_submit() {
+        let (server_sender, _server_receiver) = mpsc::channel(1);
+        let stratum = Stratum::new(server_sender, VarStr::from_string(String::new()));
+        stratum.jobs.lock().await.jobs.push_back(Job { id: "1".to_string(), template: template() });
+
+        let mut listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = stratum.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            worker.handle_worker(stream).await.unwrap();
+        });
+
+        let (reader, mut writer) = tokio::io::split(TcpStream::connect(&addr).await.unwrap());
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n").await.unwrap();
+        assert_eq!(read(&mut lines).await["id"], 1);
+        assert_eq!(read(&mut lines).await["method"], "mining.set_difficulty");
+        let notify = read(&mut lines).await;
+        assert_eq!(notify["method"], "mining.notify");
+        assert_eq!(notify["params"][0], "1");
+
+        writer.write_all(b"{\"id\":2,\"method\":\"mining.authorize\",\"params\":[\"worker\"]}\n").await.unwrap();
+        assert_eq!(read(&mut lines).await["result"], true);
+
+        // the job target is zero, so the share is checked and refused
+        let submit = b"{\"id\":3,\"method\":\"mining.submit\",\"params\":[\"worker\",\"1\",\"2a\"]}\n";
+        writer.write_all(submit).await.unwrap();
+        assert_eq!(read(&mut lines).await["error"][0], 23);
+        writer.write_all(submit).await.unwrap();
+        assert_eq!(read(&mut lines).await["error"][0], 22);
+    }
+
+    #[tokio::test]
+    async fn solution_sent_to_server() {
+        let (server_sender, mut server_receiver) = mpsc::channel(1);
+        let stratum = Stratum::new(server_sender, VarStr::from_string(String::new()));
+        // every hash meets this target
+        let easy = BlockTemplate { difficulty: vec![0xFF; 32], ..template() };
+        stratum.jobs.lock().await.jobs.push_back(Job { id: "1".to_string(), template: easy });
+
+        let mut listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = stratum.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            worker.handle_worker(stream).await.unwrap();
+        });
+
+        let (reader, mut writer) = tokio::io::split(TcpStream::connect(&addr).await.unwrap());
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"{\"id\":1,\"method\":\"mining.authorize\",\"params\":[\"worker\"]}\n").await.unwrap();
+        assert_eq!(read(&mut lines).await["result"], true);
+
+        writer.write_all(b"{\"id\":2,\"method\":\"mining.submit\",\"params\":[\"worker\",\"1\",\"2a\"]}\n").await.unwrap();
+        let response = read(&mut lines).await;
+        assert_eq!(response["result"], true);
+        assert_eq!(response["error"], Value::Null);
+        match server_receiver.recv().await {
+            Some(ServerMessage::MinedBlock(block)) => assert_eq!(block.nonce, 0x2a),
+            _ => panic!("no block sent to the server"),
+        }
+    }
+
+    async fn read<R: AsyncRead + Unpin>(lines: &mut tokio::io::Lines<BufReader<R>>) -> Value {
+        serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap()
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+use blockchain::{ Blockchain, ChainIndex };
+use blockchain::block::Block;
+use utils::Error;
+
+// a headers reply this long means the peer has more to send
+pub const MAX_HEADERS                   : usize     = 2000;
+// longest inv sent in reply to getblocks
+pub const MAX_BLOCKS_INV                : usize     = 500;
+// bodies are only requested this many blocks ahead of the tip
+const BLOCK_DOWNLOAD_WINDOW             : u32       = 1024;
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER     : usize     = 16;
+const BLOCK_STALL_TIMEOUT               : Duration  = Duration::from_secs(30);
+pub const SYNC_TICK                     : Duration  = Duration::from_secs(1);
+
+// headers-first synchronisation: the header chain is validated before any body is
+// downloaded, then bodies are fetched in parallel from every peer
+// a header chain longer than the main chain is followed even when it forks below the tip,
+// the server reorganises once its bodies are stored
+#[derive(Default)]
+pub struct HeaderSync {
+    // validated headers above the main chain tip
+    headers         : HashMap<Vec<u8>, Block>,
+    // best header chain, from the block following a stored block to the best header
+    best_chain      : VecDeque<Vec<u8>>,
+    in_flight       : HashMap<Vec<u8>, (SocketAddr, Instant)>,
+    // bodies downloaded before their parent was connected
+    received        : HashMap<Vec<u8>, Block>,
+    // the peer currently asked for headers, only one at a time
+    pub syncing_peer: Option<SocketAddr>,
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // every header we know of has its body connected
+    pub fn is_synced(&self) -> bool {
+        self.best_chain.is_empty()
+    }
+
+    // block locator starting from the best header, the header chain is used down to its first
+    // header and the main chain below it
+    pub fn locator(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let base = match self.best_chain.front() {
+            Some(first) => self.headers[first].height,
+            None => u32::MAX,
+        };
+        let mut locator = Vec::new();
+        for height in locator_heights(self.best_height()?) {
+            if height >= base {
+                locator.push(self.best_chain[(height - base) as usize].clone());
+            } else {
+                locator.push(ChainIndex::get_hash(height)?);
+            }
+        }
+        Ok(locator)
+    }
+
+    fn best_height(&self) -> Result<u32, Error> {
+        match self.best_chain.back() {
+            Some(best) => Ok(self.headers[best].height),
+            None => Ok(ChainIndex::tip()?.0),
+        }
+    }
+
+    // validates headers and extends the best header chain, returns how many were new
+    pub fn add_headers(&mut self, headers: Vec<Block>) -> Result<usize, Error> {
+        let mut added = 0;
+        for mut header in headers {
+            let hash = header.hash()?;
+            if self.headers.contains_key(&hash) || Blockchain::has_block(&hash)? {
+                continue;
+            }
+            let parent = match self.headers.get(&header.previous_hash) {
+                Some(parent) => parent.clone(),
+                // headers not connecting to anything we know are ignored
+                None => match Blockchain::get_block(&header.previous_hash) {
+                    Ok(parent) => parent,
+                    Err(_) => break,
+                },
+            };
+            if !header.check_header(&parent)? {
+                return Err(Error::BlockNotValid)
+            }
+
+            header.transactions = Vec::new();
+            let height = header.height;
+            let extends_best = match self.best_chain.back() {
+                Some(best) => *best == header.previous_hash,
+                None => ChainIndex::tip()?.1 == header.previous_hash,
+            };
+            let best_height = self.best_height()?;
+            self.headers.insert(hash.clone(), header);
+            added += 1;
+
+            if extends_best {
+                self.best_chain.push_back(hash);
+            } else if height > best_height {
+                self.switch_best_chain(hash)?;
+            }
+        }
+        Ok(added)
+    }
+
+    // a side branch of headers became the longest one, it is only used if it
+    // builds on a stored block
+    fn switch_best_chain(&mut self, best: Vec<u8>) -> Result<(), Error> {
+        let mut chain = VecDeque::new();
+        let mut hash = best;
+        while let Some(header) = self.headers.get(&hash) {
+            let previous = header.previous_hash.clone();
+            chain.push_front(hash);
+            hash = previous;
+        }
+        if Blockchain::has_block(&hash)? {
+            self.best_chain = chain;
+        }
+        Ok(())
+    }
+
+    // blocks to ask the peer for, within the download window
+    pub fn next_downloads(&mut self, peer: SocketAddr) -> Result<Vec<Vec<u8>>, Error> {
+        let in_flight = self.in_flight.values().filter(|(p, _)| *p == peer).count();
+        if in_flight >= MAX_BLOCKS_IN_FLIGHT_PER_PEER {
+            return Ok(Vec::new())
+        }
+        let (tip_height, _) = ChainIndex::tip()?;
+
+        let mut downloads = Vec::new();
+        let now = Instant::now();
+        for hash in &self.best_chain {
+            if self.headers[hash].height > tip_height + BLOCK_DOWNLOAD_WINDOW
+                || downloads.len() + in_flight >= MAX_BLOCKS_IN_FLIGHT_PER_PEER {
+                break;
+            }
+            if self.in_flight.contains_key(hash) || self.received.contains_key(hash) {
+                continue;
+            }
+            self.in_flight.insert(hash.clone(), (peer, now));
+            downloads.push(hash.clone());
+        }
+        Ok(downloads)
+    }
+
+    // returns the downloaded blocks which can now be connected, in order
+    pub fn block_received(&mut self, block: Block) -> Result<Vec<Block>, Error> {
+        let hash = block.hash()?;
+        self.in_flight.remove(&hash);
+        if !self.headers.contains_key(&hash) {
+            return Ok(vec![block])
+        }
+        self.received.insert(hash, block);
+
+        // the best chain starts after a stored block
+        let mut ready = Vec::new();
+        for hash in &self.best_chain {
+            match self.received.remove(hash) {
+                Some(block) => ready.push(block),
+                None => break,
+            }
+        }
+        Ok(ready)
+    }
+
+    // hash was stored on a side branch, the best chain now starts after it
+    pub fn block_stored(&mut self, hash: &[u8]) {
+        if let Some(position) = self.best_chain.iter().position(|h| h.as_slice() == hash) {
+            for h in self.best_chain.drain(..=position) {
+                self.headers.remove(&h);
+                self.in_flight.remove(&h);
+                self.received.remove(&h);
+            }
+        }
+    }
+
+    // hash is the new main chain tip
+    pub fn block_connected(&mut self, hash: &[u8], height: u32) {
+        self.block_stored(hash);
+        self.headers.remove(hash);
+        self.in_flight.remove(hash);
+        self.received.remove(hash);
+        // the best header chain is no longer than the main chain
+        let best_height = self.best_chain.back().map(|h| self.headers[h].height);
+        if best_height.is_some_and(|h| h <= height) {
+            for h in std::mem::take(&mut self.best_chain) {
+                self.headers.remove(&h);
+            }
+        }
+        // side branches at this height or below are shorter than the main chain
+        self.headers.retain(|_, header| header.height > height);
+        let headers = &self.headers;
+        self.in_flight.retain(|hash, _| headers.contains_key(hash));
+        self.received.retain(|hash, _| headers.contains_key(hash));
+    }
+
+    // drops a block which failed validation, with every header built on it
+    pub fn block_failed(&mut self, hash: &[u8]) {
+        if let Some(position) = self.best_chain.iter().position(|h| h.as_slice() == hash) {
+            for h in self.best_chain.split_off(position) {
+                self.headers.remove(&h);
+                self.in_flight.remove(&h);
+                self.received.remove(&h);
+            }
+        }
+    }
+
+    // releases the requests peers took too long to answer, returns those peers
+    pub fn stalled(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        self.in_flight.retain(|_, (peer, time)| {
+            if now.duration_since(*time) < BLOCK_STALL_TIMEOUT {
+                return true
+            }
+            if !peers.contains(peer) {
+                peers.push(*peer);
+            }
+            false
+        });
+        peers
+    }
+
+    // the peer doesn't have a block we asked for, it can be asked to another one
+    pub fn not_found(&mut self, peer: SocketAddr, hash: &[u8]) {
+        if let Some((p, _)) = self.in_flight.get(hash) {
+            if *p == peer {
+                self.in_flight.remove(hash);
+            }
+        }
+    }
+
+    pub fn peer_disconnected(&mut self, peer: SocketAddr) {
+        self.in_flight.retain(|_, (p, _)| *p != peer);
+        if self.syncing_peer == Some(peer) {
+            self.syncing_peer = None;
+        }
+    }
+}
+
+// the last ten heights, then exponentially spaced ones back to genesis
+fn locator_heights(mut height: u32) -> Vec<u32> {
+    let mut heights = Vec::new();
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            break;
+        }
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    heights
+}
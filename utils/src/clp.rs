@@ -4,6 +4,31 @@ use structopt::StructOpt;
 pub struct Args {
     #[structopt(short="p", long="port", default_value="4224", parse(try_from_str = "is_port"))]
     pub port    : u16,
+    #[structopt(long="stratum", parse(try_from_str = "is_port"))]
+    pub stratum : Option<u16>,
+    // json-rpc port for external miners, only bound on localhost
+    #[structopt(long="rpc", parse(try_from_str = "is_port"))]
+    pub rpc     : Option<u16>,
+    #[structopt(long="payout", default_value="")]
+    pub payout  : String,
+    // maximum memory used by the mempool, in megabytes
+    #[structopt(long="maxmempool", default_value="300")]
+    pub max_mempool : u64,
+    // outbound connections kept open
+    #[structopt(long="maxoutbound", default_value="8")]
+    pub max_outbound : usize,
+    // inbound connections accepted before evicting peers
+    #[structopt(long="maxinbound", default_value="117")]
+    pub max_inbound : usize,
+    // how long misbehaving peers stay banned, in seconds
+    #[structopt(long="bantime", default_value="86400")]
+    pub ban_time : u64,
+    // larger peer messages are refused, in bytes
+    #[structopt(long="maxmessagesize", default_value="33554432")]
+    pub max_message_size : u64,
+    // address advertised to peers, the local address of each connection otherwise
+    #[structopt(long="externalip")]
+    pub external_ip : Option<std::net::IpAddr>,
 }
 
 pub fn args() -> Args {
@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use sled::Db;
+use super::Error;
+
+thread_local! {
+    // set by tests, so that they don't share databases with each other or the node
+    static THREAD_DATA_DIR  : RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// directory holding the databases and files of the node
+pub fn data_dir() -> PathBuf {
+    match THREAD_DATA_DIR.with(|dir| dir.borrow().clone()) {
+        Some(dir) => dir,
+        None => {
+            let mut path = dirs::data_dir().unwrap();
+            path.push("ensicoin-rust/");
+            path
+        },
+    }
+}
+
+// sled keeps the lock of a dropped database for a while, opening it again right away can
+// fail; databases are opened once and the handles shared
+static DATABASES            : Mutex<BTreeMap<PathBuf, Db>> = Mutex::new(BTreeMap::new());
+
+// database stored under name in the data dir
+pub fn open_db(name: &str) -> Result<Db, Error> {
+    let path = data_dir().join(name);
+    let mut databases = DATABASES.lock().unwrap();
+    if let Some(db) = databases.get(&path) {
+        return Ok(db.clone())
+    }
+    let db = sled::open(&path)?;
+    databases.insert(path, db.clone());
+    Ok(db)
+}
+
+// data dir used by the current thread only until dropped, then removed
+pub struct TempDataDir {
+    path    : PathBuf,
+}
+
+impl TempDataDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ensicoin-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        THREAD_DATA_DIR.with(|dir| *dir.borrow_mut() = Some(path.clone()));
+        Self {
+            path,
+        }
+    }
+}
+
+impl Drop for TempDataDir {
+    fn drop(&mut self) {
+        THREAD_DATA_DIR.with(|dir| *dir.borrow_mut() = None);
+        DATABASES.lock().unwrap().retain(|path, _| !path.starts_with(&self.path));
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
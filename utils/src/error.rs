@@ -6,7 +6,14 @@ pub enum Error {
     ParseError(String),
     DBError,
     ConnectionClosed,
+    HandshakeTimeout,
+    WrongMagic,
+    MessageTooLarge,
+    WrongChecksum,
     TxNotValid,
+    BlockNotValid,
+    // the previous block is not known
+    UnknownParent,
     NoTxInUtxos,
 }
 
@@ -10,3 +10,7 @@ pub fn hash(s: Vec<u8>) -> Vec<u8> {
 pub fn hash_to_string(hash: &Vec<u8>) -> String {
     hash.iter().fold(String::new(), |acc, b| format!("{}{:02x}", acc, b))
 }
+
+pub fn hash_meets_target(hash: &[u8], target: &[u8]) -> bool {
+    hash.len() == target.len() && hash <= target
+}
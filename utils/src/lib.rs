@@ -1,8 +1,10 @@
 pub mod clp;
+pub mod datadir;
 pub mod error;
 pub mod hash;
 pub mod merkle_tree;
 
+pub use self::datadir::{ data_dir, open_db };
 pub use self::error::Error;
 pub use self::hash::*;
 pub use self::merkle_tree::*;